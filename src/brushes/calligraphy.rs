@@ -0,0 +1,100 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::cgmath::InnerSpace;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::collections::VecDeque;
+use std::f32::consts::FRAC_PI_4;
+
+use super::{Brush, PenSample, StrokeStyle};
+
+/// Flat nib whose angle follows the direction the stylus leans in and whose width
+/// grows with pressure and with how far the stylus is tilted.
+#[derive(Default)]
+pub struct CalligraphyBrush;
+
+impl CalligraphyBrush {
+    pub const NAME: &'static str = "calligraphy";
+
+    /// Tilt reported by the digitizer when the stylus lies flat.
+    const MAX_TILT: f32 = 9000.0;
+
+    /// Nib width per unit of brush size.
+    const WIDTH_PER_SIZE: f32 = 4.0;
+
+    /// Half of the nib, from its center to one edge.
+    fn nib(sample: &PenSample, style: &StrokeStyle) -> cgmath::Vector2<f32> {
+        let tilt = cgmath::vec2(sample.tilt.x as f32, sample.tilt.y as f32);
+        let lean = (tilt.magnitude() / CalligraphyBrush::MAX_TILT).min(1.0);
+        let angle = if tilt.magnitude2() > 0.0 {
+            tilt.y.atan2(tilt.x)
+        } else {
+            // Without tilt information hold the nib at the classic 45 degrees
+            FRAC_PI_4
+        };
+
+        let pressure = style.pressure_curve.factor(sample.pressure);
+        let width = style.size as f32 * CalligraphyBrush::WIDTH_PER_SIZE * pressure * (1.0 + lean);
+        cgmath::vec2(angle.cos(), angle.sin()) * (width / 2.0).max(0.5)
+    }
+}
+
+impl Brush for CalligraphyBrush {
+    fn name(&self) -> &'static str {
+        CalligraphyBrush::NAME
+    }
+
+    fn stroke_to(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        history: &VecDeque<PenSample>,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        let current = &history[history.len() - 1];
+        let prev = &history[history.len() - 2];
+        let current_nib = CalligraphyBrush::nib(current, &style);
+        let prev_nib = CalligraphyBrush::nib(prev, &style);
+
+        let corners = [
+            prev.position - prev_nib,
+            prev.position + prev_nib,
+            current.position + current_nib,
+            current.position - current_nib,
+        ];
+        if !corners.iter().all(|corner| style.in_bounds(*corner)) {
+            return mxcfb_rect::invalid();
+        }
+        let corners: Vec<cgmath::Point2<i32>> = corners.iter().map(|c| c.cast().unwrap()).collect();
+        fb.draw_polygon(&corners, true, style.color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::*;
+    use super::*;
+
+    fn stroke(tilt: cgmath::Vector2<i16>) -> Vec<(u32, u32)> {
+        let mut samples = samples(&[(60.0, 100.0), (100.0, 100.0), (140.0, 100.0)]);
+        for sample in samples.iter_mut() {
+            sample.tilt = tilt;
+        }
+        let (fb, rect) = draw_stroke(&mut CalligraphyBrush, &samples, style(2));
+        assert!(drawn_within(&fb, rect));
+        drawn_pixels(&fb)
+    }
+
+    #[test]
+    fn leaning_the_stylus_widens_the_nib() {
+        // Both hold the nib across the stroke, only how far the stylus leans differs
+        let upright = stroke(cgmath::vec2(0, 1));
+        let leaning = stroke(cgmath::vec2(0, 9000));
+        assert!(!upright.is_empty());
+        assert!(
+            leaning.len() > upright.len(),
+            "{} pixels leaning, {} upright",
+            leaning.len(),
+            upright.len()
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::cgmath::InnerSpace;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::collections::VecDeque;
+
+use super::{remember_point, Brush, PenSample, StrokeStyle};
+
+/// Harmony's chrome brush: like the web brush every nearby point gets connected,
+/// and each connection is paired with a highlight in the opposite color offset
+/// perpendicular to it, which gives the stroke its metallic sheen.
+#[derive(Default)]
+pub struct ChromeBrush {
+    points: VecDeque<cgmath::Point2<f32>>,
+}
+
+impl ChromeBrush {
+    pub const NAME: &'static str = "chrome";
+
+    /// Squared distance under which earlier points get connected.
+    const THRESHOLD: f32 = 1000.0;
+
+    /// Distance in pixels between a connection and its highlight.
+    const HIGHLIGHT_OFFSET: f32 = 2.0;
+
+    fn highlight_color(base: color) -> color {
+        match base {
+            color::WHITE => color::BLACK,
+            _ => color::WHITE,
+        }
+    }
+}
+
+impl Brush for ChromeBrush {
+    fn name(&self) -> &'static str {
+        ChromeBrush::NAME
+    }
+
+    fn begin_stroke(
+        &mut self,
+        _fb: &mut dyn FramebufferDraw,
+        sample: PenSample,
+        _style: StrokeStyle,
+    ) -> mxcfb_rect {
+        self.points.clear();
+        remember_point(&mut self.points, sample.position);
+        mxcfb_rect::invalid()
+    }
+
+    fn stroke_to(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        history: &VecDeque<PenSample>,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        let current = history.back().unwrap().position;
+        let mut rect = match self.points.back() {
+            Some(prev) => fb.draw_line(
+                prev.cast().unwrap(),
+                current.cast().unwrap(),
+                style.size.max(1),
+                style.color,
+            ),
+            None => mxcfb_rect::invalid(),
+        };
+
+        let highlight = ChromeBrush::highlight_color(style.color);
+        for point in self.points.iter() {
+            let delta = *point - current;
+            let distance = delta.magnitude2();
+            if distance >= ChromeBrush::THRESHOLD || distance == 0.0 {
+                continue;
+            }
+            let start = current + delta * 0.2;
+            let end = *point - delta * 0.2;
+            rect = rect.merge_rect(&fb.draw_line(
+                start.cast().unwrap(),
+                end.cast().unwrap(),
+                1,
+                style.color,
+            ));
+
+            // The highlight sits beside the pen's path, so it can stray off the canvas
+            let offset =
+                cgmath::vec2(-delta.y, delta.x).normalize() * ChromeBrush::HIGHLIGHT_OFFSET;
+            if style.in_bounds(start + offset) && style.in_bounds(end + offset) {
+                rect = rect.merge_rect(&fb.draw_line(
+                    (start + offset).cast().unwrap(),
+                    (end + offset).cast().unwrap(),
+                    1,
+                    highlight,
+                ));
+            }
+        }
+        remember_point(&mut self.points, current);
+        rect
+    }
+
+    fn end_stroke(&mut self, _fb: &mut dyn FramebufferDraw) -> mxcfb_rect {
+        self.points.clear();
+        mxcfb_rect::invalid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::*;
+    use super::*;
+
+    #[test]
+    fn draws_inside_its_dirty_rect() {
+        let close = samples(&[(50.0, 50.0), (60.0, 55.0), (65.0, 70.0), (75.0, 72.0)]);
+        let (fb, rect) = draw_stroke(&mut ChromeBrush::default(), &close, style(2));
+        assert!(!drawn_pixels(&fb).is_empty());
+        assert!(drawn_within(&fb, rect));
+    }
+
+    #[test]
+    fn highlights_stay_on_the_canvas() {
+        // Zigzagging along the top edge, so half of the highlights would be above
+        // it. Erasing, as only the highlights are black then.
+        let edge = CLIP.top as f32;
+        let points: Vec<(f32, f32)> = (0..12)
+            .map(|i| (40.0 + i as f32 * 8.0, edge + (i % 2) as f32 * 10.0))
+            .collect();
+        let erase = StrokeStyle {
+            color: color::WHITE,
+            ..style(1)
+        };
+        let (fb, _) = draw_stroke(&mut ChromeBrush::default(), &samples(&points), erase);
+        assert!(!drawn_pixels(&fb).is_empty());
+        assert!(drawn_within(&fb, CLIP));
+    }
+}
@@ -0,0 +1,118 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::cgmath::InnerSpace;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::collections::VecDeque;
+
+use super::rng::Rng;
+use super::{Brush, PenSample, StrokeStyle};
+
+/// Harmony's circles brush: concentric circles centered on the grid cell under
+/// the pen, spreading wider the faster the pen moves.
+pub struct CirclesBrush {
+    seed: u32,
+    rng: Rng,
+}
+
+impl CirclesBrush {
+    pub const NAME: &'static str = "circles";
+
+    /// Size of the grid cells the circles are centered in.
+    const CELL: f32 = 100.0;
+
+    const MAX_RINGS: u32 = 10;
+
+    pub fn with_seed(seed: u32) -> Self {
+        CirclesBrush {
+            seed,
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl Default for CirclesBrush {
+    fn default() -> Self {
+        CirclesBrush::with_seed(Rng::DEFAULT_SEED)
+    }
+}
+
+impl Brush for CirclesBrush {
+    fn name(&self) -> &'static str {
+        CirclesBrush::NAME
+    }
+
+    fn begin_stroke(
+        &mut self,
+        _fb: &mut dyn FramebufferDraw,
+        _sample: PenSample,
+        _style: StrokeStyle,
+    ) -> mxcfb_rect {
+        self.rng = Rng::new(self.seed);
+        mxcfb_rect::invalid()
+    }
+
+    fn stroke_to(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        history: &VecDeque<PenSample>,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        let current = history[history.len() - 1].position;
+        let prev = history[history.len() - 2].position;
+        let spread = (current - prev).magnitude() * 2.0;
+
+        let center = cgmath::Point2 {
+            x: (current.x / CirclesBrush::CELL).floor() * CirclesBrush::CELL
+                + CirclesBrush::CELL / 2.0,
+            y: (current.y / CirclesBrush::CELL).floor() * CirclesBrush::CELL
+                + CirclesBrush::CELL / 2.0,
+        };
+        let rings = (self.rng.next_f32() * CirclesBrush::MAX_RINGS as f32) as u32;
+
+        let mut rect = mxcfb_rect::invalid();
+        for ring in 0..rings {
+            let radius = (rings - ring) as f32 * spread / rings as f32;
+            let extent = cgmath::vec2(radius, radius);
+            if radius < 1.0
+                || !style.in_bounds(center - extent)
+                || !style.in_bounds(center + extent)
+            {
+                continue;
+            }
+            rect = rect.merge_rect(&fb.draw_circle(
+                center.cast().unwrap(),
+                radius as u32,
+                style.color,
+            ));
+        }
+        rect
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::*;
+    use super::*;
+
+    #[test]
+    fn draws_inside_its_dirty_rect() {
+        let samples = samples(&[
+            (150.0, 150.0),
+            (145.0, 140.0),
+            (138.0, 132.0),
+            (130.0, 125.0),
+        ]);
+        let (fb, rect) = draw_stroke(&mut CirclesBrush::default(), &samples, style(2));
+        assert!(!drawn_pixels(&fb).is_empty());
+        assert!(drawn_within(&fb, rect));
+    }
+
+    #[test]
+    fn pen_at_rest_draws_nothing() {
+        let samples = samples(&[(150.0, 150.0), (150.0, 150.0), (150.0, 150.0)]);
+        let (fb, rect) = draw_stroke(&mut CirclesBrush::default(), &samples, style(2));
+        assert_eq!(rect, mxcfb_rect::invalid());
+        assert!(drawn_pixels(&fb).is_empty());
+    }
+}
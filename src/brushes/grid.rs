@@ -0,0 +1,153 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::collections::VecDeque;
+
+use super::{Brush, PenSample, StrokeStyle};
+
+/// Cross-hatching snapped to a grid: every cell the pen passes through gets
+/// hatched once along both diagonals. The cells grow with the pen size.
+#[derive(Default)]
+pub struct GridBrush {
+    last_cell: Option<(i32, i32)>,
+}
+
+impl GridBrush {
+    pub const NAME: &'static str = "grid";
+
+    /// Size of a grid cell in pixels per unit of pen size. Pen sizes below 2
+    /// count as 2, smaller cells would hardly fit the hatching.
+    const CELL_PER_SIZE: i32 = 10;
+
+    /// Hatching lines drawn per cell in each direction, counting the one
+    /// through the corners once.
+    const LINES: i32 = 4;
+
+    fn cell_size(style: StrokeStyle) -> i32 {
+        style.size.max(2) as i32 * GridBrush::CELL_PER_SIZE
+    }
+
+    fn cell_of(point: cgmath::Point2<f32>, style: StrokeStyle) -> (i32, i32) {
+        let size = GridBrush::cell_size(style) as f32;
+        (
+            (point.x / size).floor() as i32,
+            (point.y / size).floor() as i32,
+        )
+    }
+
+    fn hatch(fb: &mut dyn FramebufferDraw, (cx, cy): (i32, i32), style: StrokeStyle) -> mxcfb_rect {
+        let size = GridBrush::cell_size(style);
+        let origin = cgmath::Point2 {
+            x: cx * size,
+            y: cy * size,
+        };
+        let far = origin + cgmath::vec2(size, size);
+        if !style.in_bounds(origin.cast().unwrap()) || !style.in_bounds(far.cast().unwrap()) {
+            return mxcfb_rect::invalid();
+        }
+
+        let mut rect = mxcfb_rect::invalid();
+        // Diagonals in the cell, shifted along its sides. The ones through the
+        // corners themselves would only be a dot.
+        for i in 1..GridBrush::LINES * 2 {
+            // Spaced in fractions of a pixel, so cells that don't split into
+            // whole steps don't get a wider gap at their far side
+            let offset = (i as f32 * size as f32 / GridBrush::LINES as f32).round() as i32;
+            let (start, end) = if offset <= size {
+                (cgmath::vec2(offset, 0), cgmath::vec2(0, offset))
+            } else {
+                let rest = offset - size;
+                (cgmath::vec2(size, rest), cgmath::vec2(rest, size))
+            };
+            rect = rect.merge_rect(&fb.draw_line(origin + start, origin + end, 1, style.color));
+            // And the same line mirrored for the other direction
+            rect = rect.merge_rect(&fb.draw_line(
+                origin + cgmath::vec2(size - start.x, start.y),
+                origin + cgmath::vec2(size - end.x, end.y),
+                1,
+                style.color,
+            ));
+        }
+        rect
+    }
+}
+
+impl Brush for GridBrush {
+    fn name(&self) -> &'static str {
+        GridBrush::NAME
+    }
+
+    fn begin_stroke(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        sample: PenSample,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        let cell = GridBrush::cell_of(sample.position, style);
+        self.last_cell = Some(cell);
+        GridBrush::hatch(fb, cell, style)
+    }
+
+    fn stroke_to(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        history: &VecDeque<PenSample>,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        let cell = GridBrush::cell_of(history.back().unwrap().position, style);
+        if self.last_cell == Some(cell) {
+            return mxcfb_rect::invalid();
+        }
+        self.last_cell = Some(cell);
+        GridBrush::hatch(fb, cell, style)
+    }
+
+    fn end_stroke(&mut self, _fb: &mut dyn FramebufferDraw) -> mxcfb_rect {
+        self.last_cell = None;
+        mxcfb_rect::invalid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::*;
+    use super::*;
+    use crate::headless::HeadlessFramebuffer;
+
+    #[test]
+    fn hatches_each_cell_once() {
+        let mut fb = HeadlessFramebuffer::new(SIZE, SIZE);
+        let mut brush = GridBrush::default();
+        // All within the cell from (60, 60) to (90, 90)
+        let samples = samples(&[(65.0, 65.0), (75.0, 70.0), (85.0, 80.0)]);
+        let rect = brush.begin_stroke(&mut fb, samples[0], style(3));
+        assert!(!drawn_pixels(&fb).is_empty());
+        assert!(drawn_within(&fb, rect));
+        for end in 2..=samples.len() {
+            let history = samples[..end].iter().copied().collect();
+            assert_eq!(
+                brush.stroke_to(&mut fb, &history, style(3)),
+                mxcfb_rect::invalid()
+            );
+        }
+    }
+
+    #[test]
+    fn hatching_is_evenly_spaced() {
+        // A 30 pixel cell, which doesn't split into whole quarters
+        let samples = samples(&[(75.0, 75.0)]);
+        let (fb, _) = draw_stroke(&mut GridBrush::default(), &samples, style(3));
+        let step = 30.0 / GridBrush::LINES as f32;
+        let top_row: Vec<u32> = drawn_pixels(&fb)
+            .iter()
+            .filter(|(_, y)| *y == 60)
+            .map(|(x, _)| x - 60)
+            .collect();
+        assert!(!top_row.is_empty());
+        for x in top_row {
+            let off_step = (x as f32 / step - (x as f32 / step).round()).abs() * step;
+            assert!(off_step <= 1.0, "line at {} is off the hatching", x);
+        }
+    }
+}
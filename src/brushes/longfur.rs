@@ -0,0 +1,139 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::collections::VecDeque;
+
+use super::rng::Rng;
+use super::{remember_point, Brush, PenSample, StrokeStyle};
+
+/// Harmony's longfur brush: long strands of random length reaching from the pen
+/// past nearby recent points of the stroke.
+pub struct LongfurBrush {
+    points: VecDeque<cgmath::Point2<f32>>,
+    seed: u32,
+    rng: Rng,
+}
+
+impl LongfurBrush {
+    pub const NAME: &'static str = "longfur";
+
+    /// Squared distance under which earlier points grow a strand.
+    const THRESHOLD: f32 = 4000.0;
+
+    pub fn with_seed(seed: u32) -> Self {
+        LongfurBrush {
+            points: VecDeque::new(),
+            seed,
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl Default for LongfurBrush {
+    fn default() -> Self {
+        LongfurBrush::with_seed(Rng::DEFAULT_SEED)
+    }
+}
+
+impl Brush for LongfurBrush {
+    fn name(&self) -> &'static str {
+        LongfurBrush::NAME
+    }
+
+    fn begin_stroke(
+        &mut self,
+        _fb: &mut dyn FramebufferDraw,
+        sample: PenSample,
+        _style: StrokeStyle,
+    ) -> mxcfb_rect {
+        self.rng = Rng::new(self.seed);
+        self.points.clear();
+        remember_point(&mut self.points, sample.position);
+        mxcfb_rect::invalid()
+    }
+
+    fn stroke_to(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        history: &VecDeque<PenSample>,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        let current = history.back().unwrap().position;
+        remember_point(&mut self.points, current);
+
+        let mut rect = mxcfb_rect::invalid();
+        for point in self.points.iter() {
+            let length = -self.rng.next_f32();
+            let delta = *point - current;
+            let distance = delta.x * delta.x + delta.y * delta.y;
+            if distance >= LongfurBrush::THRESHOLD
+                || self.rng.next_f32() <= distance / LongfurBrush::THRESHOLD
+            {
+                continue;
+            }
+            let jitter = cgmath::vec2(self.rng.next_f32() * 2.0, self.rng.next_f32() * 2.0);
+            let start = current + delta * length;
+            let end = *point - delta * length + jitter;
+            if style.in_bounds(start) && style.in_bounds(end) {
+                rect = rect.merge_rect(&fb.draw_line(
+                    start.cast().unwrap(),
+                    end.cast().unwrap(),
+                    1,
+                    style.color,
+                ));
+            }
+        }
+        rect
+    }
+
+    fn end_stroke(&mut self, _fb: &mut dyn FramebufferDraw) -> mxcfb_rect {
+        self.points.clear();
+        mxcfb_rect::invalid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::*;
+    use super::*;
+
+    fn scribble() -> Vec<PenSample> {
+        samples(&[
+            (50.0, 50.0),
+            (80.0, 60.0),
+            (60.0, 80.0),
+            (90.0, 90.0),
+            (70.0, 110.0),
+            (100.0, 120.0),
+        ])
+    }
+
+    #[test]
+    fn draws_inside_its_dirty_rect() {
+        let (fb, rect) = draw_stroke(&mut LongfurBrush::default(), &scribble(), style(2));
+        assert!(!drawn_pixels(&fb).is_empty());
+        assert!(drawn_within(&fb, rect));
+    }
+
+    #[test]
+    fn strokes_repeat_for_the_same_seed() {
+        let mut brush = LongfurBrush::with_seed(7);
+        let (first, _) = draw_stroke(&mut brush, &scribble(), style(2));
+        let (again, _) = draw_stroke(&mut brush, &scribble(), style(2));
+        let (other, _) = draw_stroke(&mut LongfurBrush::with_seed(7), &scribble(), style(2));
+        assert_eq!(drawn_pixels(&first), drawn_pixels(&again));
+        assert_eq!(drawn_pixels(&first), drawn_pixels(&other));
+    }
+
+    #[test]
+    fn strands_stay_on_the_canvas() {
+        // Zigzagging along the top edge, strands point away from it half the time
+        let edge = CLIP.top as f32;
+        let points: Vec<(f32, f32)> = (0..12)
+            .map(|i| (40.0 + i as f32 * 8.0, edge + (i % 2) as f32 * 10.0))
+            .collect();
+        let (fb, _) = draw_stroke(&mut LongfurBrush::default(), &samples(&points), style(1));
+        assert!(drawn_within(&fb, CLIP));
+    }
+}
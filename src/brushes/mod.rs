@@ -0,0 +1,358 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use crate::pressure::{PressureCurve, VelocityResponse};
+
+mod calligraphy;
+mod chrome;
+mod circles;
+mod fur;
+mod grid;
+mod longfur;
+mod ribbon;
+mod rng;
+mod shaded;
+mod simple;
+mod sketchy;
+mod spray;
+mod squares;
+mod web;
+
+pub use self::calligraphy::CalligraphyBrush;
+pub use self::chrome::ChromeBrush;
+pub use self::circles::CirclesBrush;
+pub use self::fur::FurBrush;
+pub use self::grid::GridBrush;
+pub use self::longfur::LongfurBrush;
+pub use self::ribbon::RibbonBrush;
+pub use self::shaded::ShadedBrush;
+pub use self::simple::SimpleBrush;
+pub use self::sketchy::SketchyBrush;
+pub use self::spray::SprayBrush;
+pub use self::squares::SquaresBrush;
+pub use self::web::WebBrush;
+
+/// A single digitizer sample as seen by a brush.
+#[derive(Copy, Clone, Debug)]
+pub struct PenSample {
+    pub position: cgmath::Point2<f32>,
+    pub pressure: i32,
+    /// Signed tilt of the stylus along both axes.
+    pub tilt: cgmath::Vector2<i16>,
+    pub time: Instant,
+}
+
+impl PenSample {
+    /// Speed in pixels per millisecond the pen moved at from `earlier` to here.
+    pub fn speed_since(&self, earlier: &PenSample) -> f32 {
+        let millis = self
+            .time
+            .saturating_duration_since(earlier.time)
+            .as_secs_f32()
+            * 1000.0;
+        if millis <= 0.0 {
+            return 0.0;
+        }
+        let delta = self.position - earlier.position;
+        (delta.x * delta.x + delta.y * delta.y).sqrt() / millis
+    }
+}
+
+/// Color and size the pen is currently drawing with, resolved from the draw mode
+/// and the instrument side in use, how pressure and speed affect the width, and
+/// the region brushes may draw to.
+#[derive(Copy, Clone, Debug)]
+pub struct StrokeStyle {
+    pub color: color,
+    pub size: u32,
+    pub pressure_curve: PressureCurve,
+    pub velocity: VelocityResponse,
+    pub clip: mxcfb_rect,
+}
+
+impl StrokeStyle {
+    /// Whether `point` lies within the clip region. Brushes drawing away from the
+    /// pen use this to stay inside the canvas.
+    pub fn in_bounds(&self, point: cgmath::Point2<f32>) -> bool {
+        match point.cast() {
+            Some(point) => self.clip.contains_point(&point),
+            None => false,
+        }
+    }
+}
+
+/// Most earlier points of a stroke the harmony brushes connect the pen to. Every
+/// sample is compared with each of them, so older ones get forgotten to keep
+/// long strokes from lagging behind the pen.
+const RECENT_POINTS: usize = 256;
+
+/// Adds `point` to the latest points of a stroke, forgetting the oldest one once
+/// there are `RECENT_POINTS`.
+fn remember_point(points: &mut VecDeque<cgmath::Point2<f32>>, point: cgmath::Point2<f32>) {
+    if points.len() == RECENT_POINTS {
+        points.pop_front();
+    }
+    points.push_back(point);
+}
+
+/// A procedural pen brush.
+///
+/// The input handler keeps the most recent samples of the current stroke in a
+/// short history (oldest first, the newest sample last) and hands it to the brush
+/// on every sample. Brushes only draw; every method returns the dirty rect which
+/// the caller is responsible for refreshing. `mxcfb_rect::invalid()` means nothing
+/// was drawn.
+pub trait Brush: Send {
+    fn name(&self) -> &'static str;
+
+    /// Whether the brush draws gray levels, which need a grayscale waveform
+    /// instead of the fast monochrome one.
+    fn uses_gray(&self) -> bool {
+        false
+    }
+
+    /// Called with the first sample of a stroke.
+    fn begin_stroke(
+        &mut self,
+        _fb: &mut dyn FramebufferDraw,
+        _sample: PenSample,
+        _style: StrokeStyle,
+    ) -> mxcfb_rect {
+        mxcfb_rect::invalid()
+    }
+
+    /// Called for every following sample of the stroke.
+    fn stroke_to(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        history: &VecDeque<PenSample>,
+        style: StrokeStyle,
+    ) -> mxcfb_rect;
+
+    /// Called once the pen is lifted or leaves the canvas.
+    fn end_stroke(&mut self, _fb: &mut dyn FramebufferDraw) -> mxcfb_rect {
+        mxcfb_rect::invalid()
+    }
+
+    /// Whether the stroke lags some samples behind the pen, in which case the
+    /// input handler bridges the gap with a predicted tail.
+    fn trails_pen(&self) -> bool {
+        false
+    }
+
+    /// Whether the brush keeps drawing between samples and wants `tick` called.
+    fn is_animating(&self) -> bool {
+        false
+    }
+
+    /// Advances a time-stepped brush by one frame.
+    fn tick(&mut self, _fb: &mut dyn FramebufferDraw) -> mxcfb_rect {
+        mxcfb_rect::invalid()
+    }
+}
+
+pub type BrushFactory = fn() -> Box<dyn Brush>;
+
+/// Named brush constructors, in the order they are presented to the user.
+pub struct BrushRegistry {
+    entries: Vec<(&'static str, BrushFactory)>,
+}
+
+impl BrushRegistry {
+    pub fn new() -> Self {
+        BrushRegistry {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Registers a brush under `name`, replacing any brush previously registered
+    /// with the same name.
+    pub fn register(&mut self, name: &'static str, factory: BrushFactory) {
+        match self.entries.iter_mut().find(|(n, _)| *n == name) {
+            Some(entry) => entry.1 = factory,
+            None => self.entries.push((name, factory)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.entries.iter().map(|(name, _)| *name)
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.entries.iter().position(|(n, _)| *n == name)
+    }
+
+    pub fn create(&self, name: &str) -> Option<Box<dyn Brush>> {
+        self.index_of(name).and_then(|i| self.create_at(i))
+    }
+
+    pub fn create_at(&self, index: usize) -> Option<Box<dyn Brush>> {
+        self.entries.get(index).map(|(_, factory)| factory())
+    }
+}
+
+impl Default for BrushRegistry {
+    /// A registry holding every built-in brush, `simple` first.
+    fn default() -> Self {
+        let mut registry = BrushRegistry::new();
+        registry.register(SimpleBrush::NAME, || Box::new(SimpleBrush));
+        registry.register(SketchyBrush::NAME, || Box::new(SketchyBrush::default()));
+        registry.register(ShadedBrush::NAME, || Box::new(ShadedBrush::default()));
+        registry.register(WebBrush::NAME, || Box::new(WebBrush::default()));
+        registry.register(ChromeBrush::NAME, || Box::new(ChromeBrush::default()));
+        registry.register(FurBrush::NAME, || Box::new(FurBrush::default()));
+        registry.register(LongfurBrush::NAME, || Box::new(LongfurBrush::default()));
+        registry.register(RibbonBrush::NAME, || Box::new(RibbonBrush::default()));
+        registry.register(SquaresBrush::NAME, || Box::new(SquaresBrush));
+        registry.register(CirclesBrush::NAME, || Box::new(CirclesBrush::default()));
+        registry.register(GridBrush::NAME, || Box::new(GridBrush::default()));
+        registry.register(SprayBrush::NAME, || Box::new(SprayBrush::default()));
+        registry.register(CalligraphyBrush::NAME, || Box::new(CalligraphyBrush));
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registering_a_name_again_replaces_the_brush() {
+        let mut registry = BrushRegistry::new();
+        registry.register("pen", || Box::new(SimpleBrush));
+        registry.register("other", || Box::new(SimpleBrush));
+        registry.register("pen", || Box::new(SketchyBrush::default()));
+
+        assert_eq!(registry.names().collect::<Vec<_>>(), ["pen", "other"]);
+        assert_eq!(registry.create("pen").unwrap().name(), SketchyBrush::NAME);
+        assert!(registry.create("missing").is_none());
+    }
+
+    #[test]
+    fn only_recent_points_are_remembered() {
+        let mut points = VecDeque::new();
+        for i in 0..RECENT_POINTS + 10 {
+            remember_point(&mut points, cgmath::Point2::new(i as f32, 0.0));
+        }
+        assert_eq!(points.len(), RECENT_POINTS);
+        assert_eq!(points.front().unwrap().x, 10.0);
+        assert_eq!(points.back().unwrap().x, (RECENT_POINTS + 9) as f32);
+    }
+}
+
+/// Helpers for the brush tests, drawing strokes the way a session does.
+#[cfg(test)]
+pub(crate) mod testing {
+    use libremarkable::framebuffer::cgmath;
+    use libremarkable::framebuffer::common::*;
+    use libremarkable::framebuffer::FramebufferIO;
+
+    use std::collections::VecDeque;
+    use std::time::{Duration, Instant};
+
+    use super::{Brush, PenSample, StrokeStyle};
+    use crate::headless::HeadlessFramebuffer;
+    use crate::pressure::{PressureCurve, VelocityResponse};
+
+    /// Size of the framebuffer the strokes are drawn on.
+    pub const SIZE: u32 = 200;
+
+    /// Brushes may draw anywhere but the outer 20 pixels of the framebuffer.
+    pub const CLIP: mxcfb_rect = mxcfb_rect {
+        top: 20,
+        left: 20,
+        width: SIZE - 40,
+        height: SIZE - 40,
+    };
+
+    pub fn style(size: u32) -> StrokeStyle {
+        StrokeStyle {
+            color: color::BLACK,
+            size,
+            pressure_curve: PressureCurve::Linear,
+            velocity: VelocityResponse::NONE,
+            clip: CLIP,
+        }
+    }
+
+    /// Samples through `points` 10ms apart, pressed halfway and held upright.
+    pub fn samples(points: &[(f32, f32)]) -> Vec<PenSample> {
+        let start = Instant::now();
+        points
+            .iter()
+            .enumerate()
+            .map(|(i, (x, y))| PenSample {
+                position: cgmath::Point2::new(*x, *y),
+                pressure: 2048,
+                tilt: cgmath::vec2(0, 0),
+                time: start + Duration::from_millis(i as u64 * 10),
+            })
+            .collect()
+    }
+
+    /// Draws `samples` as a single stroke, handing the brush the last three of
+    /// them like the session does and stepping it a frame after each. Returns
+    /// the framebuffer and everything the brush reported as drawn.
+    pub fn draw_stroke(
+        brush: &mut dyn Brush,
+        samples: &[PenSample],
+        style: StrokeStyle,
+    ) -> (HeadlessFramebuffer, mxcfb_rect) {
+        let mut fb = HeadlessFramebuffer::new(SIZE, SIZE);
+        let mut history = VecDeque::new();
+        let mut rect = mxcfb_rect::invalid();
+        for (i, sample) in samples.iter().enumerate() {
+            history.push_back(*sample);
+            rect = rect.merge_rect(&if i == 0 {
+                brush.begin_stroke(&mut fb, *sample, style)
+            } else {
+                brush.stroke_to(&mut fb, &history, style)
+            });
+            if brush.is_animating() {
+                rect = rect.merge_rect(&brush.tick(&mut fb));
+            }
+            while history.len() >= 3 {
+                history.pop_front();
+            }
+        }
+        rect = rect.merge_rect(&brush.end_stroke(&mut fb));
+        (fb, rect)
+    }
+
+    /// Positions of the pixels that aren't white any more.
+    pub fn drawn_pixels(fb: &HeadlessFramebuffer) -> Vec<(u32, u32)> {
+        let frame = fb
+            .dump_region(mxcfb_rect {
+                top: 0,
+                left: 0,
+                width: SIZE,
+                height: SIZE,
+            })
+            .unwrap();
+        frame
+            .chunks(2)
+            .enumerate()
+            .filter(|(_, pixel)| *pixel != [0xff, 0xff])
+            .map(|(i, _)| (i as u32 % SIZE, i as u32 / SIZE))
+            .collect()
+    }
+
+    /// Whether every pixel drawn on `fb` lies within `rect`.
+    pub fn drawn_within(fb: &HeadlessFramebuffer, rect: mxcfb_rect) -> bool {
+        drawn_pixels(fb)
+            .iter()
+            .all(|(x, y)| rect.contains_point(&cgmath::Point2::new(*x, *y)))
+    }
+}
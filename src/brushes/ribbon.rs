@@ -0,0 +1,175 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::cgmath::InnerSpace;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::collections::VecDeque;
+
+use super::rng::Rng;
+use super::{Brush, PenSample, StrokeStyle};
+
+struct Painter {
+    position: cgmath::Point2<f32>,
+    velocity: cgmath::Vector2<f32>,
+    ease: f32,
+}
+
+/// Harmony's ribbon brush: a set of spring-damped particles chase the pen and
+/// each one leaves a trail, which together form a ribbon.
+///
+/// The particles keep moving between digitizer samples, so the drawing happens in
+/// `tick` rather than in `stroke_to`. Once the pen lifts they swing on until
+/// they come to rest where it left the screen.
+pub struct RibbonBrush {
+    painters: Vec<Painter>,
+    target: cgmath::Point2<f32>,
+    style: Option<StrokeStyle>,
+    lifted: bool,
+    seed: u32,
+}
+
+impl RibbonBrush {
+    pub const NAME: &'static str = "ribbon";
+
+    const PAINTERS: usize = 50;
+
+    /// Spring stiffness pulling each particle towards the pen.
+    const STIFFNESS: f32 = 0.1;
+
+    /// Distance in pixels a particle may be off the pen, and move by in a frame,
+    /// while counting as at rest.
+    const REST: f32 = 0.5;
+
+    fn at_rest(&self) -> bool {
+        self.painters.iter().all(|painter| {
+            painter.velocity.magnitude() < RibbonBrush::REST
+                && (painter.position - self.target).magnitude() < RibbonBrush::REST
+        })
+    }
+
+    pub fn with_seed(seed: u32) -> Self {
+        RibbonBrush {
+            painters: Vec::new(),
+            target: cgmath::Point2 { x: 0.0, y: 0.0 },
+            style: None,
+            lifted: false,
+            seed,
+        }
+    }
+}
+
+impl Default for RibbonBrush {
+    fn default() -> Self {
+        RibbonBrush::with_seed(Rng::DEFAULT_SEED)
+    }
+}
+
+impl Brush for RibbonBrush {
+    fn name(&self) -> &'static str {
+        RibbonBrush::NAME
+    }
+
+    fn begin_stroke(
+        &mut self,
+        _fb: &mut dyn FramebufferDraw,
+        sample: PenSample,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        let mut rng = Rng::new(self.seed);
+        self.target = sample.position;
+        self.style = Some(style);
+        self.lifted = false;
+        self.painters = (0..RibbonBrush::PAINTERS)
+            .map(|_| Painter {
+                position: sample.position,
+                velocity: cgmath::vec2(0.0, 0.0),
+                ease: rng.next_f32() * 0.2 + 0.6,
+            })
+            .collect();
+        mxcfb_rect::invalid()
+    }
+
+    fn stroke_to(
+        &mut self,
+        _fb: &mut dyn FramebufferDraw,
+        history: &VecDeque<PenSample>,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        self.target = history.back().unwrap().position;
+        self.style = Some(style);
+        mxcfb_rect::invalid()
+    }
+
+    fn end_stroke(&mut self, _fb: &mut dyn FramebufferDraw) -> mxcfb_rect {
+        // The particles settle in the following ticks
+        self.lifted = true;
+        mxcfb_rect::invalid()
+    }
+
+    fn is_animating(&self) -> bool {
+        self.style.is_some()
+    }
+
+    fn tick(&mut self, fb: &mut dyn FramebufferDraw) -> mxcfb_rect {
+        let style = match self.style {
+            Some(style) => style,
+            None => return mxcfb_rect::invalid(),
+        };
+
+        let mut rect = mxcfb_rect::invalid();
+        for painter in self.painters.iter_mut() {
+            let previous = painter.position;
+            painter.velocity = (painter.velocity
+                + (painter.position - self.target) * RibbonBrush::STIFFNESS)
+                * painter.ease;
+            painter.position -= painter.velocity;
+            if previous != painter.position
+                && style.in_bounds(previous)
+                && style.in_bounds(painter.position)
+            {
+                rect = rect.merge_rect(&fb.draw_line(
+                    previous.cast().unwrap(),
+                    painter.position.cast().unwrap(),
+                    1,
+                    style.color,
+                ));
+            }
+        }
+        if self.lifted && self.at_rest() {
+            self.painters.clear();
+            self.style = None;
+        }
+        rect
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::*;
+    use super::*;
+    use crate::headless::HeadlessFramebuffer;
+
+    #[test]
+    fn settles_after_the_pen_lifts() {
+        let mut fb = HeadlessFramebuffer::new(SIZE, SIZE);
+        let mut brush = RibbonBrush::default();
+        // Flung towards the top edge, so the particles overshoot it
+        let samples = samples(&[(100.0, 150.0), (100.0, 25.0)]);
+        brush.begin_stroke(&mut fb, samples[0], style(1));
+        brush.stroke_to(&mut fb, &samples.iter().copied().collect(), style(1));
+        brush.tick(&mut fb);
+        brush.end_stroke(&mut fb);
+
+        let mut rect = mxcfb_rect::invalid();
+        let mut frames = 0;
+        while brush.is_animating() {
+            rect = rect.merge_rect(&brush.tick(&mut fb));
+            frames += 1;
+            assert!(frames < 1000, "still moving after {} frames", frames);
+        }
+        assert!(frames > 1);
+        assert_ne!(rect, mxcfb_rect::invalid());
+        assert!(drawn_within(&fb, CLIP));
+        assert_eq!(brush.tick(&mut fb), mxcfb_rect::invalid());
+    }
+}
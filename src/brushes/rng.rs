@@ -0,0 +1,36 @@
+/// Small xorshift generator so brushes produce the same strokes for the same seed.
+#[derive(Copy, Clone, Debug)]
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    pub const DEFAULT_SEED: u32 = 0x9e37_79b9;
+
+    pub fn new(seed: u32) -> Self {
+        // xorshift gets stuck on a zero state
+        Rng {
+            state: if seed == 0 { Rng::DEFAULT_SEED } else { seed },
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Uniformly distributed in `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1u32 << 24) as f32
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Rng::new(Rng::DEFAULT_SEED)
+    }
+}
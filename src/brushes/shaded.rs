@@ -0,0 +1,121 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::collections::VecDeque;
+
+use super::{remember_point, Brush, PenSample, StrokeStyle};
+
+/// Harmony's shaded brush: every point of the stroke is joined to the recent
+/// points around it, the closer the pair the darker the line.
+///
+/// The display can't blend, so the opacity harmony uses is emulated with gray
+/// levels which the GC16 waveform then dithers.
+#[derive(Default)]
+pub struct ShadedBrush {
+    points: VecDeque<cgmath::Point2<f32>>,
+}
+
+impl ShadedBrush {
+    pub const NAME: &'static str = "shaded";
+
+    /// Squared distance under which earlier points get connected.
+    const THRESHOLD: f32 = 1000.0;
+
+    /// Lines lighter than this gray level aren't worth a refresh.
+    const MIN_LEVEL: u8 = 16;
+
+    fn shade(base: color, closeness: f32) -> Option<color> {
+        match base {
+            // GRAY(0) is white and GRAY(255) is black
+            color::BLACK => {
+                let level = (closeness * 255.0) as u8;
+                if level < ShadedBrush::MIN_LEVEL {
+                    None
+                } else {
+                    Some(color::GRAY(level))
+                }
+            }
+            // Painting gray in erase mode would darken the page, so only the
+            // closest pairs get wiped
+            other if closeness > 0.5 => Some(other),
+            _ => None,
+        }
+    }
+}
+
+impl Brush for ShadedBrush {
+    fn name(&self) -> &'static str {
+        ShadedBrush::NAME
+    }
+
+    fn uses_gray(&self) -> bool {
+        true
+    }
+
+    fn begin_stroke(
+        &mut self,
+        _fb: &mut dyn FramebufferDraw,
+        sample: PenSample,
+        _style: StrokeStyle,
+    ) -> mxcfb_rect {
+        self.points.clear();
+        remember_point(&mut self.points, sample.position);
+        mxcfb_rect::invalid()
+    }
+
+    fn stroke_to(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        history: &VecDeque<PenSample>,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        let current = history.back().unwrap().position;
+        let mut rect = mxcfb_rect::invalid();
+        for point in self.points.iter() {
+            let delta = *point - current;
+            let distance = delta.x * delta.x + delta.y * delta.y;
+            if distance >= ShadedBrush::THRESHOLD {
+                continue;
+            }
+            let closeness = 1.0 - distance / ShadedBrush::THRESHOLD;
+            if let Some(col) = ShadedBrush::shade(style.color, closeness) {
+                rect = rect.merge_rect(&fb.draw_line(
+                    current.cast().unwrap(),
+                    point.cast().unwrap(),
+                    1,
+                    col,
+                ));
+            }
+        }
+        remember_point(&mut self.points, current);
+        rect
+    }
+
+    fn end_stroke(&mut self, _fb: &mut dyn FramebufferDraw) -> mxcfb_rect {
+        self.points.clear();
+        mxcfb_rect::invalid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::*;
+    use super::*;
+
+    #[test]
+    fn only_joins_points_close_together() {
+        let far_apart = samples(&[(40.0, 40.0), (100.0, 40.0), (160.0, 40.0)]);
+        let (fb, rect) = draw_stroke(&mut ShadedBrush::default(), &far_apart, style(2));
+        assert_eq!(rect, mxcfb_rect::invalid());
+        assert!(drawn_pixels(&fb).is_empty());
+    }
+
+    #[test]
+    fn draws_inside_its_dirty_rect() {
+        let close = samples(&[(50.0, 50.0), (60.0, 55.0), (65.0, 70.0), (75.0, 72.0)]);
+        let (fb, rect) = draw_stroke(&mut ShadedBrush::default(), &close, style(2));
+        assert!(!drawn_pixels(&fb).is_empty());
+        assert!(drawn_within(&fb, rect));
+    }
+}
@@ -0,0 +1,89 @@
+use libremarkable::framebuffer::cgmath::EuclideanSpace;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::collections::VecDeque;
+
+use super::{Brush, PenSample, StrokeStyle};
+
+/// Pressure-scaled quadratic bezier through the midpoints of the last three samples.
+#[derive(Default)]
+pub struct SimpleBrush;
+
+impl SimpleBrush {
+    pub const NAME: &'static str = "simple";
+}
+
+impl Brush for SimpleBrush {
+    fn name(&self) -> &'static str {
+        SimpleBrush::NAME
+    }
+
+    fn trails_pen(&self) -> bool {
+        // The curve ends halfway between the two newest samples
+        true
+    }
+
+    fn stroke_to(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        history: &VecDeque<PenSample>,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        if history.len() < 3 {
+            return mxcfb_rect::invalid();
+        }
+        let points: Vec<PenSample> = history.iter().rev().take(3).rev().copied().collect();
+        // Speed at each point, the middle one averaging both segments
+        let speeds = [
+            points[1].speed_since(&points[0]),
+            (points[1].speed_since(&points[0]) + points[2].speed_since(&points[1])) / 2.0,
+            points[2].speed_since(&points[1]),
+        ];
+        let radii: Vec<f32> = points
+            .iter()
+            .zip(speeds.iter())
+            .map(|(point, speed)| {
+                style.pressure_curve.radius(style.size, point.pressure)
+                    * style.velocity.factor(*speed)
+            })
+            .collect();
+        // calculate control points
+        let start_point = points[2].position.midpoint(points[1].position);
+        let ctrl_point = points[1].position;
+        let end_point = points[1].position.midpoint(points[0].position);
+        // calculate diameters
+        let start_width = radii[2] + radii[1];
+        let ctrl_width = radii[1] * 2.0;
+        let end_width = radii[1] + radii[0];
+        fb.draw_dynamic_bezier(
+            (start_point, start_width),
+            (ctrl_point, ctrl_width),
+            (end_point, end_width),
+            10,
+            style.color,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::*;
+    use super::*;
+
+    #[test]
+    fn waits_for_three_samples() {
+        let samples = samples(&[(50.0, 50.0), (70.0, 60.0)]);
+        let (fb, rect) = draw_stroke(&mut SimpleBrush, &samples, style(4));
+        assert_eq!(rect, mxcfb_rect::invalid());
+        assert!(drawn_pixels(&fb).is_empty());
+    }
+
+    #[test]
+    fn draws_inside_its_dirty_rect() {
+        let samples = samples(&[(50.0, 50.0), (70.0, 60.0), (90.0, 80.0), (120.0, 90.0)]);
+        let (fb, rect) = draw_stroke(&mut SimpleBrush, &samples, style(4));
+        assert!(!drawn_pixels(&fb).is_empty());
+        assert!(drawn_within(&fb, rect));
+    }
+}
@@ -0,0 +1,127 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::collections::VecDeque;
+
+use super::rng::Rng;
+use super::{remember_point, Brush, PenSample, StrokeStyle};
+
+/// Harmony's sketchy brush: the pen path plus faint lines bridging towards nearby
+/// points drawn recently in the same stroke.
+pub struct SketchyBrush {
+    points: VecDeque<cgmath::Point2<f32>>,
+    seed: u32,
+    rng: Rng,
+}
+
+impl SketchyBrush {
+    pub const NAME: &'static str = "sketchy";
+
+    /// Squared distance under which earlier points get connected.
+    const THRESHOLD: f32 = 4000.0;
+
+    pub fn with_seed(seed: u32) -> Self {
+        SketchyBrush {
+            points: VecDeque::new(),
+            seed,
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl Default for SketchyBrush {
+    fn default() -> Self {
+        SketchyBrush::with_seed(Rng::DEFAULT_SEED)
+    }
+}
+
+impl Brush for SketchyBrush {
+    fn name(&self) -> &'static str {
+        SketchyBrush::NAME
+    }
+
+    fn begin_stroke(
+        &mut self,
+        _fb: &mut dyn FramebufferDraw,
+        sample: PenSample,
+        _style: StrokeStyle,
+    ) -> mxcfb_rect {
+        self.rng = Rng::new(self.seed);
+        self.points.clear();
+        remember_point(&mut self.points, sample.position);
+        mxcfb_rect::invalid()
+    }
+
+    fn stroke_to(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        history: &VecDeque<PenSample>,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        let current = history.back().unwrap().position;
+        let mut rect = match self.points.back() {
+            Some(prev) => fb.draw_line(
+                prev.cast().unwrap(),
+                current.cast().unwrap(),
+                style.size.max(1),
+                style.color,
+            ),
+            None => mxcfb_rect::invalid(),
+        };
+
+        for point in self.points.iter() {
+            let delta = *point - current;
+            let distance = delta.x * delta.x + delta.y * delta.y;
+            if distance < SketchyBrush::THRESHOLD && self.rng.next_f32() > distance / 2000.0 {
+                rect = rect.merge_rect(&fb.draw_line(
+                    (current + delta * 0.3).cast().unwrap(),
+                    (*point - delta * 0.3).cast().unwrap(),
+                    1,
+                    style.color,
+                ));
+            }
+        }
+        remember_point(&mut self.points, current);
+        rect
+    }
+
+    fn end_stroke(&mut self, _fb: &mut dyn FramebufferDraw) -> mxcfb_rect {
+        self.points.clear();
+        mxcfb_rect::invalid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::*;
+    use super::*;
+
+    fn scribble() -> Vec<PenSample> {
+        samples(&[
+            (50.0, 50.0),
+            (80.0, 60.0),
+            (60.0, 80.0),
+            (90.0, 90.0),
+            (70.0, 110.0),
+            (100.0, 120.0),
+        ])
+    }
+
+    #[test]
+    fn draws_inside_its_dirty_rect() {
+        let (fb, rect) = draw_stroke(&mut SketchyBrush::default(), &scribble(), style(2));
+        assert!(!drawn_pixels(&fb).is_empty());
+        assert!(drawn_within(&fb, rect));
+    }
+
+    #[test]
+    fn strokes_repeat_for_the_same_seed() {
+        let mut brush = SketchyBrush::with_seed(7);
+        let (first, _) = draw_stroke(&mut brush, &scribble(), style(2));
+        let (again, _) = draw_stroke(&mut brush, &scribble(), style(2));
+        let (other, _) = draw_stroke(&mut SketchyBrush::with_seed(7), &scribble(), style(2));
+        assert_eq!(drawn_pixels(&first), drawn_pixels(&again));
+        assert_eq!(drawn_pixels(&first), drawn_pixels(&other));
+    }
+}
@@ -0,0 +1,147 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+use super::rng::Rng;
+use super::{Brush, PenSample, StrokeStyle};
+
+/// Airbrush scattering single pixels around the pen, the harder the press the
+/// denser the spray.
+pub struct SprayBrush {
+    seed: u32,
+    rng: Rng,
+}
+
+impl SprayBrush {
+    pub const NAME: &'static str = "spray";
+
+    /// Spray radius per unit of brush size.
+    const RADIUS_PER_SIZE: f32 = 4.0;
+
+    /// Fraction of the spray disc covered by a single burst at nominal pressure.
+    const COVERAGE: f32 = 0.05;
+
+    pub fn with_seed(seed: u32) -> Self {
+        SprayBrush {
+            seed,
+            rng: Rng::new(seed),
+        }
+    }
+
+    fn burst(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        sample: PenSample,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        let radius = (style.size as f32 * SprayBrush::RADIUS_PER_SIZE).max(2.0);
+        let pressure = style.pressure_curve.factor(sample.pressure);
+        let dots = (PI * radius * radius * SprayBrush::COVERAGE * pressure) as u32;
+
+        // Bounds of the pixels set in this burst, so it only takes one refresh
+        let mut min = cgmath::Point2 {
+            x: u32::MAX,
+            y: u32::MAX,
+        };
+        let mut max = cgmath::Point2 { x: 0, y: 0 };
+        for _ in 0..dots {
+            let angle = self.rng.next_f32() * 2.0 * PI;
+            let distance = self.rng.next_f32().sqrt() * radius;
+            let dot = sample.position + cgmath::vec2(angle.cos(), angle.sin()) * distance;
+            if !style.in_bounds(dot) {
+                continue;
+            }
+            let dot: cgmath::Point2<u32> = dot.cast().unwrap();
+            fb.fill_rect(dot.cast().unwrap(), cgmath::vec2(1, 1), style.color);
+            min = cgmath::Point2 {
+                x: min.x.min(dot.x),
+                y: min.y.min(dot.y),
+            };
+            max = cgmath::Point2 {
+                x: max.x.max(dot.x),
+                y: max.y.max(dot.y),
+            };
+        }
+
+        if min.x > max.x {
+            return mxcfb_rect::invalid();
+        }
+        mxcfb_rect {
+            top: min.y,
+            left: min.x,
+            width: max.x - min.x + 1,
+            height: max.y - min.y + 1,
+        }
+    }
+}
+
+impl Default for SprayBrush {
+    fn default() -> Self {
+        SprayBrush::with_seed(Rng::DEFAULT_SEED)
+    }
+}
+
+impl Brush for SprayBrush {
+    fn name(&self) -> &'static str {
+        SprayBrush::NAME
+    }
+
+    fn begin_stroke(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        sample: PenSample,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        self.rng = Rng::new(self.seed);
+        self.burst(fb, sample, style)
+    }
+
+    fn stroke_to(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        history: &VecDeque<PenSample>,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        self.burst(fb, *history.back().unwrap(), style)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::*;
+    use super::*;
+
+    fn spray(pressure: i32) -> Vec<(u32, u32)> {
+        let mut samples = samples(&[(100.0, 100.0)]);
+        samples[0].pressure = pressure;
+        let (fb, rect) = draw_stroke(&mut SprayBrush::default(), &samples, style(4));
+        // The whole burst is covered by the one rect it returns
+        assert!(drawn_within(&fb, rect));
+        drawn_pixels(&fb)
+    }
+
+    #[test]
+    fn harder_presses_spray_denser() {
+        let light = spray(800).len();
+        let firm = spray(3200).len();
+        assert!(light > 0);
+        assert!(
+            firm > light * 2,
+            "{} dots pressing firmly, {} lightly",
+            firm,
+            light
+        );
+    }
+
+    #[test]
+    fn dots_stay_on_the_canvas() {
+        let edge = CLIP.top as f32;
+        let samples = samples(&[(60.0, edge), (80.0, edge), (100.0, edge)]);
+        let (fb, _) = draw_stroke(&mut SprayBrush::default(), &samples, style(4));
+        assert!(!drawn_pixels(&fb).is_empty());
+        assert!(drawn_within(&fb, CLIP));
+    }
+}
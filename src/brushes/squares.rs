@@ -0,0 +1,74 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::collections::VecDeque;
+
+use super::{Brush, PenSample, StrokeStyle};
+
+/// Harmony's squares brush: every segment of the pen path becomes a square
+/// turned perpendicular to it, so faster strokes give bigger squares.
+#[derive(Default)]
+pub struct SquaresBrush;
+
+impl SquaresBrush {
+    pub const NAME: &'static str = "squares";
+
+    fn background(base: color) -> color {
+        match base {
+            color::WHITE => color::BLACK,
+            _ => color::WHITE,
+        }
+    }
+}
+
+impl Brush for SquaresBrush {
+    fn name(&self) -> &'static str {
+        SquaresBrush::NAME
+    }
+
+    fn stroke_to(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        history: &VecDeque<PenSample>,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        let current = history[history.len() - 1].position;
+        let prev = history[history.len() - 2].position;
+        let delta = current - prev;
+        // The segment turned by 90 degrees
+        let side = cgmath::vec2(-delta.y, delta.x);
+
+        let corners = [prev - side, prev + side, current + side, current - side];
+        if !corners.iter().all(|corner| style.in_bounds(*corner)) {
+            return mxcfb_rect::invalid();
+        }
+        let corners: Vec<cgmath::Point2<i32>> = corners.iter().map(|c| c.cast().unwrap()).collect();
+        fb.draw_polygon(&corners, true, SquaresBrush::background(style.color));
+        fb.draw_polygon(&corners, false, style.color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::*;
+    use super::*;
+
+    #[test]
+    fn draws_inside_its_dirty_rect() {
+        let samples = samples(&[(60.0, 60.0), (70.0, 65.0), (85.0, 80.0)]);
+        let (fb, rect) = draw_stroke(&mut SquaresBrush, &samples, style(2));
+        assert!(!drawn_pixels(&fb).is_empty());
+        assert!(drawn_within(&fb, rect));
+    }
+
+    #[test]
+    fn leaves_out_squares_crossing_the_edge() {
+        // Moving along the top edge, so every square reaches above it
+        let edge = CLIP.top as f32 + 2.0;
+        let samples = samples(&[(60.0, edge), (80.0, edge), (100.0, edge)]);
+        let (fb, rect) = draw_stroke(&mut SquaresBrush, &samples, style(2));
+        assert_eq!(rect, mxcfb_rect::invalid());
+        assert!(drawn_pixels(&fb).is_empty());
+    }
+}
@@ -0,0 +1,131 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::collections::VecDeque;
+
+use super::rng::Rng;
+use super::{remember_point, Brush, PenSample, StrokeStyle};
+
+/// Harmony's web brush: the pen path plus a sparse web of lines from the pen to
+/// recent points of the stroke around it.
+pub struct WebBrush {
+    points: VecDeque<cgmath::Point2<f32>>,
+    seed: u32,
+    rng: Rng,
+}
+
+impl WebBrush {
+    pub const NAME: &'static str = "web";
+
+    /// Squared distance under which earlier points may get connected.
+    const THRESHOLD: f32 = 2500.0;
+
+    /// Chance for a point within the threshold to get connected.
+    const DENSITY: f32 = 0.1;
+
+    pub fn with_seed(seed: u32) -> Self {
+        WebBrush {
+            points: VecDeque::new(),
+            seed,
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl Default for WebBrush {
+    fn default() -> Self {
+        WebBrush::with_seed(Rng::DEFAULT_SEED)
+    }
+}
+
+impl Brush for WebBrush {
+    fn name(&self) -> &'static str {
+        WebBrush::NAME
+    }
+
+    fn begin_stroke(
+        &mut self,
+        _fb: &mut dyn FramebufferDraw,
+        sample: PenSample,
+        _style: StrokeStyle,
+    ) -> mxcfb_rect {
+        self.rng = Rng::new(self.seed);
+        self.points.clear();
+        remember_point(&mut self.points, sample.position);
+        mxcfb_rect::invalid()
+    }
+
+    fn stroke_to(
+        &mut self,
+        fb: &mut dyn FramebufferDraw,
+        history: &VecDeque<PenSample>,
+        style: StrokeStyle,
+    ) -> mxcfb_rect {
+        let current = history.back().unwrap().position;
+        let mut rect = match self.points.back() {
+            Some(prev) => fb.draw_line(
+                prev.cast().unwrap(),
+                current.cast().unwrap(),
+                style.size.max(1),
+                style.color,
+            ),
+            None => mxcfb_rect::invalid(),
+        };
+
+        for point in self.points.iter() {
+            let delta = *point - current;
+            let distance = delta.x * delta.x + delta.y * delta.y;
+            if distance < WebBrush::THRESHOLD && self.rng.next_f32() < WebBrush::DENSITY {
+                rect = rect.merge_rect(&fb.draw_line(
+                    current.cast().unwrap(),
+                    point.cast().unwrap(),
+                    1,
+                    style.color,
+                ));
+            }
+        }
+        remember_point(&mut self.points, current);
+        rect
+    }
+
+    fn end_stroke(&mut self, _fb: &mut dyn FramebufferDraw) -> mxcfb_rect {
+        self.points.clear();
+        mxcfb_rect::invalid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::*;
+    use super::*;
+
+    fn scribble() -> Vec<PenSample> {
+        samples(&[
+            (50.0, 50.0),
+            (80.0, 60.0),
+            (60.0, 80.0),
+            (90.0, 90.0),
+            (70.0, 110.0),
+            (100.0, 120.0),
+            (80.0, 140.0),
+        ])
+    }
+
+    #[test]
+    fn draws_inside_its_dirty_rect() {
+        let (fb, rect) = draw_stroke(&mut WebBrush::default(), &scribble(), style(2));
+        assert!(!drawn_pixels(&fb).is_empty());
+        assert!(drawn_within(&fb, rect));
+    }
+
+    #[test]
+    fn strokes_repeat_for_the_same_seed() {
+        let mut brush = WebBrush::with_seed(7);
+        let (first, _) = draw_stroke(&mut brush, &scribble(), style(2));
+        let (again, _) = draw_stroke(&mut brush, &scribble(), style(2));
+        let (other, _) = draw_stroke(&mut WebBrush::with_seed(7), &scribble(), style(2));
+        assert_eq!(drawn_pixels(&first), drawn_pixels(&again));
+        assert_eq!(drawn_pixels(&first), drawn_pixels(&other));
+    }
+}
@@ -1,10 +1,7 @@
 use libremarkable::framebuffer::cgmath;
-use libremarkable::framebuffer::cgmath::EuclideanSpace;
 use libremarkable::framebuffer::common::*;
-use libremarkable::framebuffer::storage;
 use libremarkable::framebuffer::PartialRefreshMode;
 use libremarkable::framebuffer::{FramebufferDraw, FramebufferIO, FramebufferRefresh};
-use libremarkable::image::GenericImage;
 use libremarkable::input::{InputDevice, InputEvent};
 use libremarkable::ui_extensions::element::{
     UIConstraintRefresh, UIElement, UIElementHandle, UIElementWrapper,
@@ -15,268 +12,229 @@ use libremarkable::{end_bench, start_bench};
 #[cfg(feature = "enable-runtime-benchmarking")]
 use libremarkable::stopwatch;
 
-use atomic::Atomic;
 use chrono::{DateTime, Local};
 use log::info;
 use once_cell::sync::Lazy;
 
-use std::collections::VecDeque;
-use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 use std::thread::sleep;
-use std::time::Duration;
-
-#[derive(Copy, Clone, PartialEq)]
-enum DrawMode {
-    Draw(u32),
-    Erase(u32),
-}
-impl DrawMode {
-    fn set_size(self, new_size: u32) -> Self {
-        match self {
-            DrawMode::Draw(_) => DrawMode::Draw(new_size),
-            DrawMode::Erase(_) => DrawMode::Erase(new_size),
-        }
-    }
-    fn color_as_string(self) -> String {
-        match self {
-            DrawMode::Draw(_) => "Black",
-            DrawMode::Erase(_) => "White",
-        }
-        .into()
-    }
-    fn get_size(self) -> u32 {
-        match self {
-            DrawMode::Draw(s) => s,
-            DrawMode::Erase(s) => s,
-        }
-    }
+use std::time::{Duration, Instant};
+
+use harmonizers::document;
+use harmonizers::drawings::{self, Drawing};
+use harmonizers::export;
+use harmonizers::headless::HeadlessFramebuffer;
+use harmonizers::pressure::{PressureCurve, VelocityResponse};
+use harmonizers::recording::{self, Operation, Recorder};
+use harmonizers::{DrawMode, Session, CANVAS_REGION};
+
+// Settings and drawings live here unless HARMONIZERS_DATA_DIR says otherwise
+const DEFAULT_DATA_DIR: &str = "/home/root/.local/share/harmonizers";
+const DRAWINGS_PER_PAGE: usize = 4;
+
+/// Everything the app keeps between events, in one place. Handlers are handed
+/// it rather than reaching for globals of their own, but the app still has a
+/// single one: see `STATE`.
+struct State {
+    session: Session,
+    // Times the logo was tapped
+    counter: u32,
+    // Page of saved drawings shown in the browser
+    drawings_page: usize,
+    // Input gets written here when HARMONIZERS_RECORD names a file
+    recorder: Option<Recorder>,
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
-enum TouchMode {
-    OnlyUI,
-    Bezier,
-    Circles,
-    Diamonds,
-    FillDiamonds,
-}
-impl TouchMode {
-    fn toggle(self) -> Self {
-        match self {
-            TouchMode::OnlyUI => TouchMode::Bezier,
-            TouchMode::Bezier => TouchMode::Circles,
-            TouchMode::Circles => TouchMode::Diamonds,
-            TouchMode::Diamonds => TouchMode::FillDiamonds,
-            TouchMode::FillDiamonds => TouchMode::OnlyUI,
+impl State {
+    fn new() -> Self {
+        State {
+            session: new_session(),
+            counter: 0,
+            drawings_page: 0,
+            recorder: None,
         }
     }
 }
 
-impl fmt::Display for TouchMode {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mode = match self {
-            TouchMode::OnlyUI => "None",
-            TouchMode::Bezier => "Bezier",
-            TouchMode::Circles => "Circles",
-            TouchMode::Diamonds => "Diamonds",
-            TouchMode::FillDiamonds => "FDiamonds",
-        };
-        write!(f, "{}", mode)
-    }
-}
-
-// This region will have the following size at rest:
-//   raw: 5896 kB
-//   zstd: 10 kB
-const CANVAS_REGION: mxcfb_rect = mxcfb_rect {
-    top: 720,
-    left: 0,
-    height: 1080,
-    width: 1404,
-};
-
-static G_TOUCH_MODE: Lazy<Atomic<TouchMode>> = Lazy::new(|| Atomic::new(TouchMode::OnlyUI));
-static G_DRAW_MODE: Lazy<Atomic<DrawMode>> = Lazy::new(|| Atomic::new(DrawMode::Draw(2)));
-static UNPRESS_OBSERVED: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(false));
-static WACOM_IN_RANGE: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(false));
-static WACOM_RUBBER_SIDE: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(false));
-static WACOM_HISTORY: Lazy<Mutex<VecDeque<(cgmath::Point2<f32>, i32)>>> =
-    Lazy::new(|| Mutex::new(VecDeque::new()));
-static G_COUNTER: Lazy<Mutex<u32>> = Lazy::new(|| Mutex::new(0));
-static SAVED_CANVAS: Lazy<Mutex<Option<storage::CompressedCanvasState>>> =
-    Lazy::new(|| Mutex::new(None));
+// libremarkable calls UI and input handlers through plain function pointers,
+// which can't carry the state along, so they and the tick thread running
+// beside them pick it up from here
+static STATE: Lazy<Mutex<State>> = Lazy::new(|| Mutex::new(State::new()));
 
 // ####################
 // ## Button Handlers
 // ####################
 
-fn on_save_canvas(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+fn on_save_canvas(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    _element: UIElementHandle,
+) {
     start_bench!(stopwatch, save_canvas);
     let framebuffer = app.get_framebuffer_ref();
     match framebuffer.dump_region(CANVAS_REGION) {
         Err(err) => println!("Failed to dump buffer: {0}", err),
         Ok(buff) => {
-            let mut hist = SAVED_CANVAS.lock().unwrap();
-            *hist = Some(storage::CompressedCanvasState::new(
-                buff.as_slice(),
-                CANVAS_REGION.height,
+            let dir = drawings_dir();
+            // Saving twice within a second gets the second drawing numbered
+            let name = drawings::unused_name(
+                &dir,
+                &Local::now().format("drawing-%Y%m%d-%H%M%S").to_string(),
+            );
+            let drawing = Drawing::new(
                 CANVAS_REGION.width,
-            ));
+                CANVAS_REGION.height,
+                buff,
+                state.session.document().clone(),
+            );
+            match drawing.save(&drawings::path(&dir, &name)) {
+                Err(err) => println!("Failed to save drawing: {0}", err),
+                Ok(_) => {
+                    state.drawings_page = 0;
+                    update_drawings_browser(state, app);
+                }
+            }
         }
     };
     end_bench!(save_canvas);
 }
 
-fn on_zoom_out(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
-    start_bench!(stopwatch, zoom_out);
+fn on_export_png(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    start_bench!(stopwatch, export_png);
     let framebuffer = app.get_framebuffer_ref();
     match framebuffer.dump_region(CANVAS_REGION) {
         Err(err) => println!("Failed to dump buffer: {0}", err),
         Ok(buff) => {
-            let resized = image::DynamicImage::ImageRgb8(
-                storage::rgbimage_from_u8_slice(
-                    CANVAS_REGION.width,
-                    CANVAS_REGION.height,
-                    buff.as_slice(),
-                )
-                .unwrap(),
-            )
-            .resize(
-                (CANVAS_REGION.width as f32 / 1.25f32) as u32,
-                (CANVAS_REGION.height as f32 / 1.25f32) as u32,
-                image::imageops::Nearest,
-            );
-
-            // Get a clean image the size of the canvas
-            let mut new_image =
-                image::DynamicImage::new_rgb8(CANVAS_REGION.width, CANVAS_REGION.height);
-            new_image.invert();
-
-            // Copy the resized image into the subimage
-            new_image
-                .copy_from(&resized, CANVAS_REGION.width / 8, CANVAS_REGION.height / 8)
-                .unwrap();
-
-            framebuffer.draw_image(
-                new_image.as_rgb8().unwrap(),
-                CANVAS_REGION.top_left().cast().unwrap(),
-            );
-            framebuffer.partial_refresh(
-                &CANVAS_REGION,
-                PartialRefreshMode::Async,
-                waveform_mode::WAVEFORM_MODE_GC16_FAST,
-                display_temp::TEMP_USE_REMARKABLE_DRAW,
-                dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                0,
-                false,
-            );
+            let path = export_path("png");
+            match export::write_png(&path, CANVAS_REGION.width, CANVAS_REGION.height, &buff) {
+                Err(err) => println!("Failed to export PNG: {0}", err),
+                Ok(_) => info!("Exported canvas to {}", path.display()),
+            }
         }
     };
-    end_bench!(zoom_out);
+    end_bench!(export_png);
 }
 
-fn on_blur_canvas(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
-    start_bench!(stopwatch, blur_canvas);
+fn on_export_svg(
+    state: &mut State,
+    _app: &mut appctx::ApplicationContext<'_>,
+    _element: UIElementHandle,
+) {
+    start_bench!(stopwatch, export_svg);
+    let path = export_path("svg");
+    let session = &state.session;
+    match export::write_svg(&path, session.document(), session.registry(), CANVAS_REGION) {
+        Err(err) => println!("Failed to export SVG: {0}", err),
+        Ok(_) => info!("Exported strokes to {}", path.display()),
+    }
+    end_bench!(export_svg);
+}
+
+fn on_export_pdf(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    _element: UIElementHandle,
+) {
+    start_bench!(stopwatch, export_pdf);
     let framebuffer = app.get_framebuffer_ref();
     match framebuffer.dump_region(CANVAS_REGION) {
         Err(err) => println!("Failed to dump buffer: {0}", err),
         Ok(buff) => {
-            let dynamic = image::DynamicImage::ImageRgb8(
-                storage::rgbimage_from_u8_slice(
-                    CANVAS_REGION.width,
-                    CANVAS_REGION.height,
-                    buff.as_slice(),
-                )
-                .unwrap(),
-            )
-            .blur(0.6f32);
-
-            framebuffer.draw_image(
-                dynamic.as_rgb8().unwrap(),
-                CANVAS_REGION.top_left().cast().unwrap(),
-            );
-            framebuffer.partial_refresh(
-                &CANVAS_REGION,
-                PartialRefreshMode::Async,
-                waveform_mode::WAVEFORM_MODE_GC16_FAST,
-                display_temp::TEMP_USE_REMARKABLE_DRAW,
-                dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                0,
-                false,
-            );
+            let path = export_path("pdf");
+            let session = &state.session;
+            let strokes = Some((session.document(), session.registry()));
+            match export::write_pdf(&path, CANVAS_REGION, &buff, strokes) {
+                Err(err) => println!("Failed to export PDF: {0}", err),
+                Ok(_) => info!("Exported canvas to {}", path.display()),
+            }
         }
     };
+    end_bench!(export_pdf);
+}
+
+fn on_zoom_out(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    _element: UIElementHandle,
+) {
+    start_bench!(stopwatch, zoom_out);
+    record_operation(state, Operation::ZoomOut);
+    if let Err(err) = state.session.zoom_out(app.get_framebuffer_ref()) {
+        println!("Failed to zoom out: {0}", err);
+    }
+    end_bench!(zoom_out);
+}
+
+fn on_blur_canvas(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    _element: UIElementHandle,
+) {
+    start_bench!(stopwatch, blur_canvas);
+    record_operation(state, Operation::Blur);
+    if let Err(err) = state.session.blur(app.get_framebuffer_ref()) {
+        println!("Failed to blur canvas: {0}", err);
+    }
     end_bench!(blur_canvas);
 }
 
-fn on_invert_canvas(app: &mut appctx::ApplicationContext<'_>, element: UIElementHandle) {
+fn on_invert_canvas(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    element: UIElementHandle,
+) {
     start_bench!(stopwatch, invert);
-    let framebuffer = app.get_framebuffer_ref();
-    match framebuffer.dump_region(CANVAS_REGION) {
-        Err(err) => println!("Failed to dump buffer: {0}", err),
-        Ok(mut buff) => {
-            buff.iter_mut().for_each(|p| {
-                *p = !(*p);
-            });
-            match framebuffer.restore_region(CANVAS_REGION, &buff) {
-                Err(e) => println!("Error while restoring region: {0}", e),
-                Ok(_) => {
-                    framebuffer.partial_refresh(
-                        &CANVAS_REGION,
-                        PartialRefreshMode::Async,
-                        waveform_mode::WAVEFORM_MODE_GC16_FAST,
-                        display_temp::TEMP_USE_REMARKABLE_DRAW,
-                        dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                        0,
-                        false,
-                    );
-                }
-            };
-        }
-    };
+    record_operation(state, Operation::Invert);
+    if let Err(err) = state.session.invert(app.get_framebuffer_ref()) {
+        println!("Failed to invert canvas: {0}", err);
+    }
     end_bench!(invert);
 
     // Invert the draw color as well for more natural UX
-    on_toggle_eraser(app, element);
+    on_toggle_eraser(state, app, element);
 }
 
-fn on_load_canvas(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
-    start_bench!(stopwatch, load_canvas);
-    match *SAVED_CANVAS.lock().unwrap() {
-        None => {}
-        Some(ref compressed_state) => {
-            let framebuffer = app.get_framebuffer_ref();
-            let decompressed = compressed_state.decompress();
-
-            match framebuffer.restore_region(CANVAS_REGION, &decompressed) {
-                Err(e) => println!("Error while restoring region: {0}", e),
-                Ok(_) => {
-                    framebuffer.partial_refresh(
-                        &CANVAS_REGION,
-                        PartialRefreshMode::Async,
-                        waveform_mode::WAVEFORM_MODE_GC16_FAST,
-                        display_temp::TEMP_USE_REMARKABLE_DRAW,
-                        dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
-                        0,
-                        false,
-                    );
-                }
-            };
+/// Loads the most recently saved drawing.
+fn on_load_canvas(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    _element: UIElementHandle,
+) {
+    match drawings::list(&drawings_dir()) {
+        Err(err) => println!("Failed to list drawings: {0}", err),
+        Ok(names) => {
+            if let Some(name) = names.first() {
+                load_drawing(state, app, name);
+            }
         }
+    }
+}
+
+fn on_open_drawing(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    element: UIElementHandle,
+) {
+    let name = match element.read().inner {
+        UIElement::Text { ref text, .. } => text.clone(),
+        _ => return,
     };
-    end_bench!(load_canvas);
+    if !name.is_empty() {
+        load_drawing(state, app, &name);
+    }
 }
 
-fn on_touch_rustlogo(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+fn on_touch_rustlogo(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    _element: UIElementHandle,
+) {
     let framebuffer = app.get_framebuffer_ref();
-    let new_press_count = {
-        let mut v = G_COUNTER.lock().unwrap();
-        *v += 1;
-        *v
-    };
+    state.counter += 1;
+    let new_press_count = state.counter;
 
     // First drawing with GC16_FAST to draw it thoroughly and then
     // alternating between DU which has more artifacts but is faster.
@@ -307,23 +265,33 @@ fn on_touch_rustlogo(app: &mut appctx::ApplicationContext<'_>, _element: UIEleme
     );
 }
 
-fn on_toggle_eraser(app: &mut appctx::ApplicationContext<'_>, _: UIElementHandle) {
-    let (new_mode, name) = match G_DRAW_MODE.load(Ordering::Relaxed) {
+fn on_toggle_eraser(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    _: UIElementHandle,
+) {
+    let (new_mode, name) = match state.session.draw_mode {
         DrawMode::Erase(s) => (DrawMode::Draw(s), "Black".to_owned()),
         DrawMode::Draw(s) => (DrawMode::Erase(s), "White".to_owned()),
     };
-    G_DRAW_MODE.store(new_mode, Ordering::Relaxed);
+    state.session.draw_mode = new_mode;
 
     let indicator = app.get_element_by_name("colorIndicator");
     if let UIElement::Text { ref mut text, .. } = indicator.unwrap().write().inner {
         *text = name;
     }
     app.draw_element("colorIndicator");
+    // Each color keeps its own speed response
+    update_velocity_indicator(state, app);
 }
 
-fn on_change_touchdraw_mode(app: &mut appctx::ApplicationContext<'_>, _: UIElementHandle) {
-    let new_val = G_TOUCH_MODE.load(Ordering::Relaxed).toggle();
-    G_TOUCH_MODE.store(new_val, Ordering::Relaxed);
+fn on_change_touchdraw_mode(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    _: UIElementHandle,
+) {
+    let new_val = state.session.touch_mode.toggle();
+    state.session.touch_mode = new_val;
 
     let indicator = app.get_element_by_name("touchModeIndicator");
     if let UIElement::Text { ref mut text, .. } = indicator.unwrap().write().inner {
@@ -334,20 +302,187 @@ fn on_change_touchdraw_mode(app: &mut appctx::ApplicationContext<'_>, _: UIEleme
     app.draw_element("touchModeIndicator");
 }
 
+fn on_select_pen_brush(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    element: UIElementHandle,
+) {
+    let name = match element.read().inner {
+        UIElement::Text { ref text, .. } => text.clone(),
+        _ => return,
+    };
+    if !state.session.select_brush(&name) {
+        return;
+    }
+
+    let indicator = app.get_element_by_name("penBrushIndicator");
+    if let UIElement::Text { ref mut text, .. } = indicator.unwrap().write().inner {
+        *text = format!("Pen: {0}", name);
+    }
+    app.draw_element("penBrushIndicator");
+}
+
+fn on_change_pressure_curve(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    _: UIElementHandle,
+) {
+    let new_val = state.session.pressure_curve.toggle();
+    state.session.pressure_curve = new_val;
+    if let Err(err) = new_val.save(&pressure_curve_path()) {
+        println!("Failed to save pressure curve: {0}", err);
+    }
+
+    let indicator = app.get_element_by_name("pressureCurveIndicator");
+    if let UIElement::Text { ref mut text, .. } = indicator.unwrap().write().inner {
+        *text = new_val.name().to_owned();
+    }
+    app.draw_element("pressureCurveIndicator");
+}
+
+fn on_toggle_velocity(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    _: UIElementHandle,
+) {
+    let new_val = if state.session.velocity() == VelocityResponse::NONE {
+        VelocityResponse::THINNING
+    } else {
+        VelocityResponse::NONE
+    };
+    state.session.set_velocity(new_val);
+    update_velocity_indicator(state, app);
+}
+
+fn on_toggle_stabilizer(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    _: UIElementHandle,
+) {
+    state.session.stabilizer_enabled = !state.session.stabilizer_enabled;
+    update_stabilizer_indicator(state, app);
+}
+
+fn on_undo(state: &mut State, app: &mut appctx::ApplicationContext<'_>, _: UIElementHandle) {
+    record_operation(state, Operation::Undo);
+    if let Err(err) = state.session.undo(app.get_framebuffer_ref()) {
+        println!("Failed to undo: {0}", err);
+    }
+}
+
+fn on_redo(state: &mut State, app: &mut appctx::ApplicationContext<'_>, _: UIElementHandle) {
+    record_operation(state, Operation::Redo);
+    if let Err(err) = state.session.redo(app.get_framebuffer_ref()) {
+        println!("Failed to redo: {0}", err);
+    }
+}
+
 // ####################
 // ## Miscellaneous
 // ####################
 
+/// A session for the canvas, drawing with the pressure curve picked last time.
+fn new_session() -> Session {
+    let mut session = Session::new(CANVAS_REGION);
+    session.pressure_curve = PressureCurve::load(&pressure_curve_path()).unwrap_or_default();
+    session
+}
+
+fn data_dir() -> PathBuf {
+    std::env::var_os("HARMONIZERS_DATA_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_DIR))
+}
+
+fn pressure_curve_path() -> PathBuf {
+    data_dir().join("pressure_curve")
+}
+
+fn drawings_dir() -> PathBuf {
+    data_dir().join("drawings")
+}
+
+/// Timestamped path for a new export with the given extension.
+fn export_path(extension: &str) -> PathBuf {
+    let name = Local::now().format("harmonizers-%Y%m%d-%H%M%S").to_string();
+    data_dir()
+        .join("exports")
+        .join(name)
+        .with_extension(extension)
+}
+
+/// Replaces the canvas with a saved drawing.
+fn load_drawing(state: &mut State, app: &mut appctx::ApplicationContext<'_>, name: &str) {
+    start_bench!(stopwatch, load_canvas);
+    let path = drawings::path(&drawings_dir(), name);
+    record_operation(state, Operation::Load(path.clone()));
+    let loaded = Drawing::load(&path).and_then(|drawing| {
+        state
+            .session
+            .load_drawing(app.get_framebuffer_ref(), &drawing)
+    });
+    if let Err(err) = loaded {
+        println!("Failed to load drawing {0}: {1}", name, err);
+    }
+    end_bench!(load_canvas);
+}
+
+fn change_drawings_page(state: &mut State, app: &mut appctx::ApplicationContext<'_>, delta: i32) {
+    let page = state.drawings_page as i32 + delta;
+    state.drawings_page = page.max(0) as usize;
+    update_drawings_browser(state, app);
+}
+
+/// Lists the current page of saved drawings in the browser.
+fn update_drawings_browser(state: &mut State, app: &mut appctx::ApplicationContext<'_>) {
+    let names = match drawings::list(&drawings_dir()) {
+        Ok(names) => names,
+        Err(err) => {
+            println!("Failed to list drawings: {0}", err);
+            Vec::new()
+        }
+    };
+    let pages = names.len().div_ceil(DRAWINGS_PER_PAGE).max(1);
+    let page = state.drawings_page.min(pages - 1);
+    state.drawings_page = page;
+
+    let indicator = app.get_element_by_name("drawingsPage");
+    if let UIElement::Text { ref mut text, .. } = indicator.unwrap().write().inner {
+        *text = format!("{0}/{1}", page + 1, pages);
+    }
+    app.draw_element("drawingsPage");
+
+    let mut shown = names.iter().skip(page * DRAWINGS_PER_PAGE);
+    for i in 0..DRAWINGS_PER_PAGE {
+        let element_name = format!("drawing{}", i);
+        let entry = app.get_element_by_name(&element_name);
+        if let UIElement::Text { ref mut text, .. } = entry.unwrap().write().inner {
+            *text = shown.next().cloned().unwrap_or_default();
+        }
+        app.draw_element(&element_name);
+    }
+}
+
 /// Called on button press on rm2 or left gpio on rm1
-fn quick_redraw(app: &mut appctx::ApplicationContext<'_>) {
+fn quick_redraw(state: &mut State, app: &mut appctx::ApplicationContext<'_>) {
     app.clear(false);
     app.draw_elements();
+    reset_session(state, app);
 }
 
 /// Called on button press on rm2 or middle gpio on rm1
-fn full_redraw(app: &mut appctx::ApplicationContext<'_>) {
+fn full_redraw(state: &mut State, app: &mut appctx::ApplicationContext<'_>) {
     app.clear(true);
     app.draw_elements();
+    reset_session(state, app);
+}
+
+/// Starts the canvas over with an empty document and undo history.
+fn reset_session(state: &mut State, app: &mut appctx::ApplicationContext<'_>) {
+    record_operation(state, Operation::Clear);
+    if let Err(err) = state.session.reset(app.get_framebuffer_ref()) {
+        println!("Failed to reset history: {0}", err);
+    }
 }
 
 /// Called on button press (pen can press, too) on rm2 or right gpio on rm1
@@ -377,7 +512,11 @@ fn toggle_touch(app: &mut appctx::ApplicationContext<'_>) {
     app.draw_element("toggleTouch");
 }
 
-fn draw_color_test_rgb(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+fn draw_color_test_rgb(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    _element: UIElementHandle,
+) {
     let fb = app.get_framebuffer_ref();
 
     let img_rgb565 = image::load_from_memory(include_bytes!("../assets/colorspace.png")).unwrap();
@@ -385,6 +524,9 @@ fn draw_color_test_rgb(app: &mut appctx::ApplicationContext<'_>, _element: UIEle
         img_rgb565.as_rgb8().unwrap(),
         CANVAS_REGION.top_left().cast().unwrap(),
     );
+    if let Err(err) = state.session.record_canvas(fb) {
+        println!("Failed to record edit: {0}", err);
+    }
     fb.partial_refresh(
         &CANVAS_REGION,
         PartialRefreshMode::Wait,
@@ -396,8 +538,8 @@ fn draw_color_test_rgb(app: &mut appctx::ApplicationContext<'_>, _element: UIEle
     );
 }
 
-fn change_brush_width(app: &mut appctx::ApplicationContext<'_>, delta: i32) {
-    let current = G_DRAW_MODE.load(Ordering::Relaxed);
+fn change_brush_width(state: &mut State, app: &mut appctx::ApplicationContext<'_>, delta: i32) {
+    let current = state.session.draw_mode;
     let current_size = current.get_size() as i32;
     let proposed_size = current_size + delta;
     let new_size = if proposed_size < 1 {
@@ -411,7 +553,7 @@ fn change_brush_width(app: &mut appctx::ApplicationContext<'_>, delta: i32) {
         return;
     }
 
-    G_DRAW_MODE.store(current.set_size(new_size as u32), Ordering::Relaxed);
+    state.session.draw_mode = current.set_size(new_size as u32);
 
     let element = app.get_element_by_name("displaySize").unwrap();
     if let UIElement::Text { ref mut text, .. } = element.write().inner {
@@ -420,6 +562,49 @@ fn change_brush_width(app: &mut appctx::ApplicationContext<'_>, delta: i32) {
     app.draw_element("displaySize");
 }
 
+fn update_velocity_indicator(state: &mut State, app: &mut appctx::ApplicationContext<'_>) {
+    let indicator = app.get_element_by_name("velocityIndicator");
+    if let UIElement::Text { ref mut text, .. } = indicator.unwrap().write().inner {
+        *text = velocity_label(&state.session);
+    }
+    app.draw_element("velocityIndicator");
+}
+
+fn velocity_label(session: &Session) -> String {
+    if session.velocity() == VelocityResponse::NONE {
+        "Even".to_owned()
+    } else {
+        "Thins".to_owned()
+    }
+}
+
+fn change_stabilizer_strength(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    delta: i32,
+) {
+    let stabilizer = &mut state.session.stabilizer;
+    let strength = stabilizer.strength() as i32 + delta;
+    stabilizer.set_strength(strength.max(0) as u32);
+    update_stabilizer_indicator(state, app);
+}
+
+fn update_stabilizer_indicator(state: &mut State, app: &mut appctx::ApplicationContext<'_>) {
+    let indicator = app.get_element_by_name("stabilizerIndicator");
+    if let UIElement::Text { ref mut text, .. } = indicator.unwrap().write().inner {
+        *text = stabilizer_label(&state.session);
+    }
+    app.draw_element("stabilizerIndicator");
+}
+
+fn stabilizer_label(session: &Session) -> String {
+    if session.stabilizer_enabled {
+        format!("{0:<3}", session.stabilizer.strength())
+    } else {
+        "off".to_owned()
+    }
+}
+
 fn loop_update_topbar(app: &mut appctx::ApplicationContext<'_>, millis: u64) {
     let time_label = app.get_element_by_name("time").unwrap();
     let battery_label = app.get_element_by_name("battery").unwrap();
@@ -447,195 +632,48 @@ fn loop_update_topbar(app: &mut appctx::ApplicationContext<'_>, millis: u64) {
     }
 }
 
+/// Steps animated brushes while they draw, during a stroke and while they settle after it
+fn loop_tick_brush(app: &mut appctx::ApplicationContext<'_>, millis: u64) {
+    loop {
+        STATE
+            .lock()
+            .unwrap()
+            .session
+            .tick(app.get_framebuffer_ref());
+        sleep(Duration::from_millis(millis));
+    }
+}
+
 // ####################
 // ## Input Handlers
 // ####################
 
-fn on_wacom_input(app: &mut appctx::ApplicationContext<'_>, input: input::WacomEvent) {
-    match input {
-        input::WacomEvent::Draw {
-            position,
-            pressure,
-            tilt: _,
-        } => {
-            let mut wacom_stack = WACOM_HISTORY.lock().unwrap();
-
-            // This is so that we can click the buttons outside the canvas region
-            // normally meant to be touched with a finger using our stylus
-            if !CANVAS_REGION.contains_point(&position.cast().unwrap()) {
-                wacom_stack.clear();
-                if UNPRESS_OBSERVED.fetch_and(false, Ordering::Relaxed) {
-                    let region = app
-                        .find_active_region(position.y.round() as u16, position.x.round() as u16);
-                    let element = region.map(|(region, _)| region.element.clone());
-                    if let Some(element) = element {
-                        (region.unwrap().0.handler)(app, element)
-                    }
-                }
-                return;
-            }
-
-            let (mut col, mut mult) = match G_DRAW_MODE.load(Ordering::Relaxed) {
-                DrawMode::Draw(s) => (color::BLACK, s),
-                DrawMode::Erase(s) => (color::WHITE, s * 3),
-            };
-            if WACOM_RUBBER_SIDE.load(Ordering::Relaxed) {
-                col = match col {
-                    color::WHITE => color::BLACK,
-                    _ => color::WHITE,
-                };
-                mult = 50; // Rough size of the rubber end
-            }
-
-            wacom_stack.push_back((position.cast().unwrap(), pressure as i32));
-
-            while wacom_stack.len() >= 3 {
-                let framebuffer = app.get_framebuffer_ref();
-                let points = vec![
-                    wacom_stack.pop_front().unwrap(),
-                    *wacom_stack.get(0).unwrap(),
-                    *wacom_stack.get(1).unwrap(),
-                ];
-                let radii: Vec<f32> = points
-                    .iter()
-                    .map(|point| ((mult as f32 * (point.1 as f32) / 2048.) / 2.0))
-                    .collect();
-                // calculate control points
-                let start_point = points[2].0.midpoint(points[1].0);
-                let ctrl_point = points[1].0;
-                let end_point = points[1].0.midpoint(points[0].0);
-                // calculate diameters
-                let start_width = radii[2] + radii[1];
-                let ctrl_width = radii[1] * 2.0;
-                let end_width = radii[1] + radii[0];
-                let rect = framebuffer.draw_dynamic_bezier(
-                    (start_point, start_width),
-                    (ctrl_point, ctrl_width),
-                    (end_point, end_width),
-                    10,
-                    col,
-                );
-
-                framebuffer.partial_refresh(
-                    &rect,
-                    PartialRefreshMode::Async,
-                    waveform_mode::WAVEFORM_MODE_DU,
-                    display_temp::TEMP_USE_REMARKABLE_DRAW,
-                    dither_mode::EPDC_FLAG_EXP1,
-                    DRAWING_QUANT_BIT,
-                    false,
-                );
-            }
-        }
-        input::WacomEvent::InstrumentChange { pen, state } => {
-            match pen {
-                // Whether the pen is in range
-                input::WacomPen::ToolPen => {
-                    WACOM_IN_RANGE.store(state, Ordering::Relaxed);
-                    WACOM_RUBBER_SIDE.store(false, Ordering::Relaxed);
-                }
-                input::WacomPen::ToolRubber => {
-                    WACOM_IN_RANGE.store(state, Ordering::Relaxed);
-                    WACOM_RUBBER_SIDE.store(true, Ordering::Relaxed);
-                }
-                // Whether the pen is actually making contact
-                input::WacomPen::Touch => {
-                    // Stop drawing when instrument has left the vicinity of the screen
-                    if !state {
-                        let mut wacom_stack = WACOM_HISTORY.lock().unwrap();
-                        wacom_stack.clear();
-                    }
-                }
-                _ => unreachable!(),
-            }
-        }
-        input::WacomEvent::Hover {
-            position: _,
-            distance,
-            tilt: _,
-        } => {
-            // If the pen is hovering, don't record its coordinates as the origin of the next line
-            if distance > 1 {
-                let mut wacom_stack = WACOM_HISTORY.lock().unwrap();
-                wacom_stack.clear();
-                UNPRESS_OBSERVED.store(true, Ordering::Relaxed);
-            }
-        }
-        _ => {}
-    };
+/// Returns where the pen tapped outside the canvas.
+fn on_wacom_input(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    input: input::WacomEvent,
+) -> Option<cgmath::Point2<f32>> {
+    state
+        .session
+        .wacom_input(app.get_framebuffer_ref(), input, Instant::now())
 }
 
-fn on_touch_handler(app: &mut appctx::ApplicationContext<'_>, input: input::MultitouchEvent) {
-    let framebuffer = app.get_framebuffer_ref();
-    match input {
-        input::MultitouchEvent::Press { finger } | input::MultitouchEvent::Move { finger } => {
-            if !CANVAS_REGION.contains_point(&finger.pos.cast().unwrap()) {
-                return;
-            }
-            let rect = match G_TOUCH_MODE.load(Ordering::Relaxed) {
-                TouchMode::Bezier => {
-                    let position_float = finger.pos.cast().unwrap();
-                    let points = vec![
-                        (cgmath::vec2(-40.0, 0.0), 2.5),
-                        (cgmath::vec2(40.0, -60.0), 5.5),
-                        (cgmath::vec2(0.0, 0.0), 3.5),
-                        (cgmath::vec2(-40.0, 60.0), 6.5),
-                        (cgmath::vec2(-10.0, 50.0), 5.0),
-                        (cgmath::vec2(10.0, 45.0), 4.5),
-                        (cgmath::vec2(30.0, 55.0), 3.5),
-                        (cgmath::vec2(50.0, 65.0), 3.0),
-                        (cgmath::vec2(70.0, 40.0), 0.0),
-                    ];
-                    let mut rect = mxcfb_rect::invalid();
-                    for window in points.windows(3).step_by(2) {
-                        rect = rect.merge_rect(&framebuffer.draw_dynamic_bezier(
-                            (position_float + window[0].0, window[0].1),
-                            (position_float + window[1].0, window[1].1),
-                            (position_float + window[2].0, window[2].1),
-                            100,
-                            color::BLACK,
-                        ));
-                    }
-                    rect
-                }
-                TouchMode::Circles => {
-                    framebuffer.draw_circle(finger.pos.cast().unwrap(), 20, color::BLACK)
-                }
-
-                m @ TouchMode::Diamonds | m @ TouchMode::FillDiamonds => {
-                    let position_int = finger.pos.cast().unwrap();
-                    framebuffer.draw_polygon(
-                        &[
-                            position_int + cgmath::vec2(-10, 0),
-                            position_int + cgmath::vec2(0, 20),
-                            position_int + cgmath::vec2(10, 0),
-                            position_int + cgmath::vec2(0, -20),
-                        ],
-                        match m {
-                            TouchMode::Diamonds => false,
-                            TouchMode::FillDiamonds => true,
-                            _ => false,
-                        },
-                        color::BLACK,
-                    )
-                }
-                _ => return,
-            };
-            framebuffer.partial_refresh(
-                &rect,
-                PartialRefreshMode::Async,
-                waveform_mode::WAVEFORM_MODE_DU,
-                display_temp::TEMP_USE_REMARKABLE_DRAW,
-                dither_mode::EPDC_FLAG_USE_DITHERING_ALPHA,
-                DRAWING_QUANT_BIT,
-                false,
-            );
-        }
-        _ => {}
-    }
+fn on_touch_handler(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    input: input::MultitouchEvent,
+) {
+    state
+        .session
+        .touch_input(app.get_framebuffer_ref(), input, Instant::now());
 }
 
-fn on_button_press(app: &mut appctx::ApplicationContext<'_>, input: input::GPIOEvent) {
+fn on_button_press(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    input: input::GPIOEvent,
+) {
     let (btn, new_state) = match input {
         input::GPIOEvent::Press { button } => (button, true),
         input::GPIOEvent::Unpress { button } => (button, false),
@@ -648,15 +686,14 @@ fn on_button_press(app: &mut appctx::ApplicationContext<'_>, input: input::GPIOE
     }
 
     // Simple but effective accidental button press filtering
-    if WACOM_IN_RANGE.load(Ordering::Relaxed) {
+    if state.session.pen_in_range() {
         return;
     }
 
     match btn {
-        input::PhysicalButton::LEFT => quick_redraw(app),
-        input::PhysicalButton::MIDDLE => full_redraw(app),
+        input::PhysicalButton::LEFT => quick_redraw(state, app),
+        input::PhysicalButton::MIDDLE => full_redraw(state, app),
         input::PhysicalButton::RIGHT => toggle_touch(app),
-
         input::PhysicalButton::POWER => {
             Command::new("systemctl")
                 .arg("start")
@@ -671,9 +708,109 @@ fn on_button_press(app: &mut appctx::ApplicationContext<'_>, input: input::GPIOE
     };
 }
 
+/// Records the event if asked to and dispatches it to its handler, returning
+/// where the pen tapped outside the canvas.
+fn on_input(
+    state: &mut State,
+    app: &mut appctx::ApplicationContext<'_>,
+    event: InputEvent,
+) -> Option<cgmath::Point2<f32>> {
+    if let Some(recorder) = state.recorder.as_mut() {
+        // Whatever the buttons changed since the last event goes first, so a
+        // replay draws the event with the same settings
+        let recorded = recorder
+            .record_settings(&state.session.settings())
+            .and_then(|_| recorder.record(&event));
+        if let Err(err) = recorded {
+            println!("Failed to record input: {0}", err);
+        }
+    }
+    match event {
+        InputEvent::WacomEvent { event } => return on_wacom_input(state, app, event),
+        InputEvent::MultitouchEvent { event } => on_touch_handler(state, app, event),
+        InputEvent::GPIO { event } => on_button_press(state, app, event),
+        _ => {}
+    };
+    None
+}
+
+/// Records what a button around the canvas does to it, if asked to record.
+fn record_operation(state: &mut State, operation: Operation) {
+    if let Some(recorder) = state.recorder.as_mut() {
+        // Like for input, so a replay applies it with the same settings
+        let recorded = recorder
+            .record_settings(&state.session.settings())
+            .and_then(|_| recorder.record_operation(&operation));
+        if let Err(err) = recorded {
+            println!("Failed to record {0:?}: {1}", operation, err);
+        }
+    }
+}
+
+/// Runs the handler of the UI element at `position`, if there is one.
+fn tap(app: &mut appctx::ApplicationContext<'_>, position: cgmath::Point2<f32>) {
+    let region = app.find_active_region(position.y.round() as u16, position.x.round() as u16);
+    let element = region.map(|(region, _)| region.element.clone());
+    if let Some(element) = element {
+        (region.unwrap().0.handler)(app, element)
+    }
+}
+
+// ####################
+// ## Replay
+// ####################
+
+/// Feeds a recording through a session against a headless framebuffer and
+/// writes the canvas it drew to a PNG.
+fn replay(recording: &Path, output: &Path) -> io::Result<()> {
+    let events = recording::load(recording)?;
+    let mut framebuffer = HeadlessFramebuffer::default();
+    // Start from the defaults rather than this machine's settings, the
+    // recording says what was changed
+    let mut session = Session::new(CANVAS_REGION);
+    session.reset(&framebuffer)?;
+    session.replay(&mut framebuffer, &events);
+    info!(
+        "Replayed {} events, asking for {} refreshes",
+        events.len(),
+        framebuffer.take_refreshes().len()
+    );
+    let pixels = framebuffer
+        .dump_region(CANVAS_REGION)
+        .map_err(io::Error::other)?;
+    export::write_png(output, CANVAS_REGION.width, CANVAS_REGION.height, &pixels)
+}
+
 fn main() {
     env_logger::init();
 
+    // Drawing a recording doesn't need a device, so this happens before
+    // anything touches the framebuffer
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("replay") {
+        match args.as_slice() {
+            [_, recording, output] => {
+                if let Err(err) = replay(Path::new(recording), Path::new(output)) {
+                    println!("Failed to replay input: {0}", err);
+                    std::process::exit(1);
+                }
+            }
+            _ => {
+                println!("Usage: harmonizers replay <recording> <output.png>");
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+
+    let mut state = STATE.lock().unwrap();
+    if let Some(path) = std::env::var_os("HARMONIZERS_RECORD") {
+        match Recorder::create(Path::new(&path)) {
+            Err(err) => println!("Failed to start recording input: {0}", err),
+            Ok(recorder) => state.recorder = Some(recorder),
+        }
+    }
+
     // Takes callback functions as arguments
     // They are called with the event and the &mut framebuffer
     let mut app: appctx::ApplicationContext<'_> = appctx::ApplicationContext::default();
@@ -693,7 +830,9 @@ fn main() {
                // Create a clickable region for multitouch input and associate it with its handler fn
                app.create_active_region(10, 900, 240, 480, on_touch_rustlogo);
             */
-            onclick: Some(on_touch_rustlogo),
+            onclick: Some(|appctx, element| {
+                on_touch_rustlogo(&mut STATE.lock().unwrap(), appctx, element)
+            }),
             inner: UIElement::Image {
                 img: image::load_from_memory(include_bytes!("../assets/rustlang.png")).unwrap(),
             },
@@ -720,10 +859,12 @@ fn main() {
     app.add_element(
         "colortest-rgb",
         UIElementWrapper {
-            position: cgmath::Point2 { x: 960, y: 300 },
+            position: cgmath::Point2 { x: 960, y: 290 },
             refresh: UIConstraintRefresh::Refresh,
 
-            onclick: Some(draw_color_test_rgb),
+            onclick: Some(|appctx, element| {
+                draw_color_test_rgb(&mut STATE.lock().unwrap(), appctx, element)
+            }),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Show RGB Test Image".to_owned(),
@@ -738,10 +879,12 @@ fn main() {
     app.add_element(
         "zoomoutButton",
         UIElementWrapper {
-            position: cgmath::Point2 { x: 960, y: 370 },
+            position: cgmath::Point2 { x: 960, y: 340 },
             refresh: UIConstraintRefresh::Refresh,
 
-            onclick: Some(on_zoom_out),
+            onclick: Some(|appctx, element| {
+                on_zoom_out(&mut STATE.lock().unwrap(), appctx, element)
+            }),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Zoom Out".to_owned(),
@@ -755,10 +898,12 @@ fn main() {
     app.add_element(
         "blurToggle",
         UIElementWrapper {
-            position: cgmath::Point2 { x: 1155, y: 370 },
+            position: cgmath::Point2 { x: 1155, y: 340 },
             refresh: UIConstraintRefresh::Refresh,
 
-            onclick: Some(on_blur_canvas),
+            onclick: Some(|appctx, element| {
+                on_blur_canvas(&mut STATE.lock().unwrap(), appctx, element)
+            }),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Blur".to_owned(),
@@ -772,10 +917,12 @@ fn main() {
     app.add_element(
         "invertToggle",
         UIElementWrapper {
-            position: cgmath::Point2 { x: 1247, y: 370 },
+            position: cgmath::Point2 { x: 1247, y: 340 },
             refresh: UIConstraintRefresh::Refresh,
 
-            onclick: Some(on_invert_canvas),
+            onclick: Some(|appctx, element| {
+                on_invert_canvas(&mut STATE.lock().unwrap(), appctx, element)
+            }),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Invert".to_owned(),
@@ -790,10 +937,12 @@ fn main() {
     app.add_element(
         "saveButton",
         UIElementWrapper {
-            position: cgmath::Point2 { x: 960, y: 440 },
+            position: cgmath::Point2 { x: 960, y: 395 },
             refresh: UIConstraintRefresh::Refresh,
 
-            onclick: Some(on_save_canvas),
+            onclick: Some(|appctx, element| {
+                on_save_canvas(&mut STATE.lock().unwrap(), appctx, element)
+            }),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Save".to_owned(),
@@ -807,10 +956,12 @@ fn main() {
     app.add_element(
         "restoreButton",
         UIElementWrapper {
-            position: cgmath::Point2 { x: 1080, y: 440 },
+            position: cgmath::Point2 { x: 1080, y: 395 },
             refresh: UIConstraintRefresh::Refresh,
 
-            onclick: Some(on_load_canvas),
+            onclick: Some(|appctx, element| {
+                on_load_canvas(&mut STATE.lock().unwrap(), appctx, element)
+            }),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Load".to_owned(),
@@ -821,14 +972,51 @@ fn main() {
         },
     );
 
+    // Undo/Redo Controls
+    app.add_element(
+        "undoButton",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 1190, y: 395 },
+            refresh: UIConstraintRefresh::Refresh,
+
+            onclick: Some(|appctx, element| on_undo(&mut STATE.lock().unwrap(), appctx, element)),
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: "Undo".to_owned(),
+                scale: 45.0,
+                border_px: 5,
+            },
+            ..Default::default()
+        },
+    );
+
+    app.add_element(
+        "redoButton",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 1295, y: 395 },
+            refresh: UIConstraintRefresh::Refresh,
+
+            onclick: Some(|appctx, element| on_redo(&mut STATE.lock().unwrap(), appctx, element)),
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: "Redo".to_owned(),
+                scale: 45.0,
+                border_px: 5,
+            },
+            ..Default::default()
+        },
+    );
+
     // Touch Mode Toggle
     app.add_element(
         "touchMode",
         UIElementWrapper {
-            position: cgmath::Point2 { x: 960, y: 510 },
+            position: cgmath::Point2 { x: 960, y: 450 },
             refresh: UIConstraintRefresh::Refresh,
 
-            onclick: Some(on_change_touchdraw_mode),
+            onclick: Some(|appctx, element| {
+                on_change_touchdraw_mode(&mut STATE.lock().unwrap(), appctx, element)
+            }),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Touch Mode".to_owned(),
@@ -841,7 +1029,7 @@ fn main() {
     app.add_element(
         "touchModeIndicator",
         UIElementWrapper {
-            position: cgmath::Point2 { x: 1210, y: 510 },
+            position: cgmath::Point2 { x: 1210, y: 450 },
             refresh: UIConstraintRefresh::Refresh,
 
             onclick: None,
@@ -859,10 +1047,12 @@ fn main() {
     app.add_element(
         "colorToggle",
         UIElementWrapper {
-            position: cgmath::Point2 { x: 960, y: 580 },
+            position: cgmath::Point2 { x: 960, y: 505 },
             refresh: UIConstraintRefresh::Refresh,
 
-            onclick: Some(on_toggle_eraser),
+            onclick: Some(|appctx, element| {
+                on_toggle_eraser(&mut STATE.lock().unwrap(), appctx, element)
+            }),
             inner: UIElement::Text {
                 foreground: color::BLACK,
                 text: "Draw Color".to_owned(),
@@ -875,19 +1065,125 @@ fn main() {
     app.add_element(
         "colorIndicator",
         UIElementWrapper {
-            position: cgmath::Point2 { x: 1210, y: 580 },
+            position: cgmath::Point2 { x: 1210, y: 505 },
+            refresh: UIConstraintRefresh::Refresh,
+
+            onclick: None,
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: state.session.draw_mode.color_as_string(),
+                scale: 40.0,
+                border_px: 0,
+            },
+            ..Default::default()
+        },
+    );
+
+    // Speed Response Toggle
+    app.add_element(
+        "velocityToggle",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 960, y: 560 },
+            refresh: UIConstraintRefresh::Refresh,
+
+            onclick: Some(|appctx, element| {
+                on_toggle_velocity(&mut STATE.lock().unwrap(), appctx, element)
+            }),
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: "Speed Width".to_owned(),
+                scale: 45.0,
+                border_px: 5,
+            },
+            ..Default::default()
+        },
+    );
+    app.add_element(
+        "velocityIndicator",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 1210, y: 560 },
+            refresh: UIConstraintRefresh::Refresh,
+
+            onclick: None,
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: velocity_label(&state.session),
+                scale: 40.0,
+                border_px: 0,
+            },
+            ..Default::default()
+        },
+    );
+
+    // Stabilizer Controls
+    app.add_element(
+        "stabilizerToggle",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 960, y: 615 },
+            refresh: UIConstraintRefresh::Refresh,
+
+            onclick: Some(|appctx, element| {
+                on_toggle_stabilizer(&mut STATE.lock().unwrap(), appctx, element)
+            }),
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: "Smooth".to_owned(),
+                scale: 45.0,
+                border_px: 5,
+            },
+            ..Default::default()
+        },
+    );
+    app.add_element(
+        "stabilizerIndicator",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 1120, y: 615 },
             refresh: UIConstraintRefresh::Refresh,
 
             onclick: None,
             inner: UIElement::Text {
                 foreground: color::BLACK,
-                text: G_DRAW_MODE.load(Ordering::Relaxed).color_as_string(),
+                text: stabilizer_label(&state.session),
                 scale: 40.0,
                 border_px: 0,
             },
             ..Default::default()
         },
     );
+    app.add_element(
+        "decreaseStabilizer",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 1210, y: 615 },
+            refresh: UIConstraintRefresh::Refresh,
+            onclick: Some(|appctx, _| {
+                change_stabilizer_strength(&mut STATE.lock().unwrap(), appctx, -1);
+            }),
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: "-".to_owned(),
+                scale: 60.0,
+                border_px: 5,
+            },
+            ..Default::default()
+        },
+    );
+    app.add_element(
+        "increaseStabilizer",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 1260, y: 615 },
+            refresh: UIConstraintRefresh::Refresh,
+            onclick: Some(|appctx, _| {
+                change_stabilizer_strength(&mut STATE.lock().unwrap(), appctx, 1);
+            }),
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: "+".to_owned(),
+                scale: 60.0,
+                border_px: 5,
+            },
+            ..Default::default()
+        },
+    );
 
     // Size Controls
     app.add_element(
@@ -896,7 +1192,7 @@ fn main() {
             position: cgmath::Point2 { x: 960, y: 670 },
             refresh: UIConstraintRefresh::Refresh,
             onclick: Some(|appctx, _| {
-                change_brush_width(appctx, -10);
+                change_brush_width(&mut STATE.lock().unwrap(), appctx, -10);
             }),
             inner: UIElement::Text {
                 foreground: color::BLACK,
@@ -913,7 +1209,7 @@ fn main() {
             position: cgmath::Point2 { x: 1030, y: 670 },
             refresh: UIConstraintRefresh::Refresh,
             onclick: Some(|appctx, _| {
-                change_brush_width(appctx, -1);
+                change_brush_width(&mut STATE.lock().unwrap(), appctx, -1);
             }),
             inner: UIElement::Text {
                 foreground: color::BLACK,
@@ -931,7 +1227,7 @@ fn main() {
             refresh: UIConstraintRefresh::Refresh,
             inner: UIElement::Text {
                 foreground: color::BLACK,
-                text: format!("size: {0}", G_DRAW_MODE.load(Ordering::Relaxed).get_size()),
+                text: format!("size: {0}", state.session.draw_mode.get_size()),
                 scale: 45.0,
                 border_px: 0,
             },
@@ -944,7 +1240,7 @@ fn main() {
             position: cgmath::Point2 { x: 1240, y: 670 },
             refresh: UIConstraintRefresh::Refresh,
             onclick: Some(|appctx, _| {
-                change_brush_width(appctx, 1);
+                change_brush_width(&mut STATE.lock().unwrap(), appctx, 1);
             }),
             inner: UIElement::Text {
                 foreground: color::BLACK,
@@ -961,7 +1257,7 @@ fn main() {
             position: cgmath::Point2 { x: 1295, y: 670 },
             refresh: UIConstraintRefresh::Refresh,
             onclick: Some(|appctx, _| {
-                change_brush_width(appctx, 10);
+                change_brush_width(&mut STATE.lock().unwrap(), appctx, 10);
             }),
             inner: UIElement::Text {
                 foreground: color::BLACK,
@@ -1017,62 +1313,247 @@ fn main() {
             ..Default::default()
         },
     );
+    // Pen Brush Palette
+    let brush_names: Vec<&str> = state.session.registry().names().collect();
+    for (i, &name) in brush_names.iter().enumerate() {
+        app.add_element(
+            &format!("penBrush_{}", name),
+            UIElementWrapper {
+                position: cgmath::Point2 {
+                    x: 30 + (i % 3) as i32 * 180,
+                    y: 285 + (i / 3) as i32 * 45,
+                },
+                refresh: UIConstraintRefresh::Refresh,
+
+                onclick: Some(|appctx, element| {
+                    on_select_pen_brush(&mut STATE.lock().unwrap(), appctx, element)
+                }),
+                inner: UIElement::Text {
+                    foreground: color::BLACK,
+                    text: name.to_owned(),
+                    scale: 30.0,
+                    border_px: 3,
+                },
+                ..Default::default()
+            },
+        );
+    }
     app.add_element(
-        "l1",
+        "penBrushIndicator",
         UIElementWrapper {
-            position: cgmath::Point2 { x: 30, y: 350 },
+            position: cgmath::Point2 { x: 30, y: 520 },
             refresh: UIConstraintRefresh::Refresh,
+
+            onclick: None,
             inner: UIElement::Text {
                 foreground: color::BLACK,
-                text: "Low Latency eInk Display Partial Refresh API".to_owned(),
-                scale: 45.0,
+                text: format!("Pen: {0}", brush_names[state.session.brush_index()]),
+                scale: 35.0,
                 border_px: 0,
             },
             ..Default::default()
         },
     );
+
+    // Pressure Curve Toggle
     app.add_element(
-        "l3",
+        "pressureCurve",
         UIElementWrapper {
-            position: cgmath::Point2 { x: 30, y: 400 },
+            position: cgmath::Point2 { x: 340, y: 520 },
             refresh: UIConstraintRefresh::Refresh,
+
+            onclick: Some(|appctx, element| {
+                on_change_pressure_curve(&mut STATE.lock().unwrap(), appctx, element)
+            }),
             inner: UIElement::Text {
                 foreground: color::BLACK,
-                text: "Capacitive Multitouch Input Support".to_owned(),
-                scale: 45.0,
+                text: "Curve".to_owned(),
+                scale: 35.0,
+                border_px: 3,
+            },
+            ..Default::default()
+        },
+    );
+    app.add_element(
+        "pressureCurveIndicator",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 460, y: 520 },
+            refresh: UIConstraintRefresh::Refresh,
+
+            onclick: None,
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: state.session.pressure_curve.name().to_owned(),
+                scale: 35.0,
                 border_px: 0,
             },
             ..Default::default()
         },
     );
+
+    // Saved Drawings Browser
     app.add_element(
-        "l2",
+        "drawingsTitle",
         UIElementWrapper {
-            position: cgmath::Point2 { x: 30, y: 450 },
+            position: cgmath::Point2 { x: 600, y: 285 },
             refresh: UIConstraintRefresh::Refresh,
+
+            onclick: None,
             inner: UIElement::Text {
                 foreground: color::BLACK,
-                text: "Physical Button Support".to_owned(),
-                scale: 45.0,
+                text: "Drawings".to_owned(),
+                scale: 35.0,
                 border_px: 0,
             },
             ..Default::default()
         },
     );
     app.add_element(
-        "l4",
+        "previousDrawings",
         UIElementWrapper {
-            position: cgmath::Point2 { x: 30, y: 500 },
+            position: cgmath::Point2 { x: 750, y: 285 },
             refresh: UIConstraintRefresh::Refresh,
+            onclick: Some(|appctx, _| {
+                change_drawings_page(&mut STATE.lock().unwrap(), appctx, -1);
+            }),
             inner: UIElement::Text {
                 foreground: color::BLACK,
-                text: "Wacom Digitizer Support".to_owned(),
-                scale: 45.0,
+                text: "<".to_owned(),
+                scale: 35.0,
+                border_px: 3,
+            },
+            ..Default::default()
+        },
+    );
+    app.add_element(
+        "drawingsPage",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 790, y: 285 },
+            refresh: UIConstraintRefresh::Refresh,
+
+            onclick: None,
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: "1/1".to_owned(),
+                scale: 35.0,
+                border_px: 0,
+            },
+            ..Default::default()
+        },
+    );
+    app.add_element(
+        "nextDrawings",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 870, y: 285 },
+            refresh: UIConstraintRefresh::Refresh,
+            onclick: Some(|appctx, _| {
+                change_drawings_page(&mut STATE.lock().unwrap(), appctx, 1);
+            }),
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: ">".to_owned(),
+                scale: 35.0,
+                border_px: 3,
+            },
+            ..Default::default()
+        },
+    );
+    // Filled in by update_drawings_browser once the scene is drawn
+    for i in 0..DRAWINGS_PER_PAGE {
+        app.add_element(
+            &format!("drawing{}", i),
+            UIElementWrapper {
+                position: cgmath::Point2 {
+                    x: 600,
+                    y: 330 + i as i32 * 45,
+                },
+                refresh: UIConstraintRefresh::Refresh,
+
+                onclick: Some(|appctx, element| {
+                    on_open_drawing(&mut STATE.lock().unwrap(), appctx, element)
+                }),
+                inner: UIElement::Text {
+                    foreground: color::BLACK,
+                    text: String::new(),
+                    scale: 30.0,
+                    border_px: 0,
+                },
+                ..Default::default()
+            },
+        );
+    }
+
+    // Export Controls
+    app.add_element(
+        "exportTitle",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 600, y: 510 },
+            refresh: UIConstraintRefresh::Refresh,
+
+            onclick: None,
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: "Export".to_owned(),
+                scale: 35.0,
                 border_px: 0,
             },
             ..Default::default()
         },
     );
+    app.add_element(
+        "exportPng",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 720, y: 510 },
+            refresh: UIConstraintRefresh::Refresh,
+
+            onclick: Some(on_export_png),
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: "PNG".to_owned(),
+                scale: 30.0,
+                border_px: 3,
+            },
+            ..Default::default()
+        },
+    );
+
+    app.add_element(
+        "exportSvg",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 800, y: 510 },
+            refresh: UIConstraintRefresh::Refresh,
+
+            onclick: Some(|appctx, element| {
+                on_export_svg(&mut STATE.lock().unwrap(), appctx, element)
+            }),
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: "SVG".to_owned(),
+                scale: 30.0,
+                border_px: 3,
+            },
+            ..Default::default()
+        },
+    );
+
+    app.add_element(
+        "exportPdf",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 880, y: 510 },
+            refresh: UIConstraintRefresh::Refresh,
+
+            onclick: Some(|appctx, element| {
+                on_export_pdf(&mut STATE.lock().unwrap(), appctx, element)
+            }),
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: "PDF".to_owned(),
+                scale: 30.0,
+                border_px: 3,
+            },
+            ..Default::default()
+        },
+    );
 
     let is_rm_2 = libremarkable::device::CURRENT_DEVICE.model == libremarkable::device::Model::Gen2;
 
@@ -1082,7 +1563,7 @@ fn main() {
             position: cgmath::Point2 { x: 15, y: 1850 },
             refresh: UIConstraintRefresh::Refresh,
             onclick: if is_rm_2 {
-                Some(|app, _| quick_redraw(app))
+                Some(|app, _| quick_redraw(&mut STATE.lock().unwrap(), app))
             } else {
                 None
             },
@@ -1101,7 +1582,7 @@ fn main() {
             position: cgmath::Point2 { x: 565, y: 1850 },
             refresh: UIConstraintRefresh::Refresh,
             onclick: if is_rm_2 {
-                Some(|app, _| full_redraw(app))
+                Some(|app, _| full_redraw(&mut STATE.lock().unwrap(), app))
             } else {
                 None
             },
@@ -1173,12 +1654,20 @@ fn main() {
 
     // Draw the scene
     app.draw_elements();
+    update_drawings_browser(&mut state, &mut app);
+    reset_session(&mut state, &mut app);
+    // Handlers take the state from here on
+    drop(state);
 
     // Get a &mut to the framebuffer object, exposing many convenience functions
     let appref = app.upgrade_ref();
     let clock_thread = std::thread::spawn(move || {
         loop_update_topbar(appref, 30 * 1000);
     });
+    let appref = app.upgrade_ref();
+    std::thread::spawn(move || {
+        loop_tick_brush(appref, document::TICK_INTERVAL.as_millis() as u64);
+    });
 
     app.execute_lua(
         r#"
@@ -1195,7 +1684,8 @@ fn main() {
         end
       end
 
-      top = 430;
+      -- Below the drawings browser and the export buttons
+      top = 540;
       left = 570;
       width = 320;
       height = 90;
@@ -1213,11 +1703,14 @@ fn main() {
     info!("Init complete. Beginning event dispatch...");
 
     // Blocking call to process events from digitizer + touchscreen + physical buttons
-    app.start_event_loop(true, true, true, |ctx, evt| match evt {
-        InputEvent::WacomEvent { event } => on_wacom_input(ctx, event),
-        InputEvent::MultitouchEvent { event } => on_touch_handler(ctx, event),
-        InputEvent::GPIO { event } => on_button_press(ctx, event),
-        _ => {}
+    app.start_event_loop(true, true, true, |ctx, evt| {
+        let tapped = on_input(&mut STATE.lock().unwrap(), ctx, evt);
+        // This is so that we can click the buttons outside the canvas region
+        // normally meant to be touched with a finger using our stylus. The
+        // state is unlocked again by now, the button handler takes it itself.
+        if let Some(position) = tapped {
+            tap(ctx, position);
+        }
     });
     clock_thread.join().unwrap();
 }
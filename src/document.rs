@@ -0,0 +1,472 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::brushes::{BrushRegistry, PenSample, StrokeStyle};
+use crate::pressure::VelocityResponse;
+use crate::touch::TouchMode;
+
+/// Interval animated brushes are stepped at while drawing. Replaying a stroke
+/// steps them once per interval of recorded time, so the result is the same on
+/// every render but may differ slightly from what was drawn live.
+pub const TICK_INTERVAL: Duration = Duration::from_millis(1000 / 60);
+
+/// One input sample of a stroke, timed from the start of the stroke.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StrokePoint {
+    pub position: cgmath::Point2<f32>,
+    pub pressure: i32,
+    pub tilt: cgmath::Vector2<i16>,
+    pub time: Duration,
+}
+
+/// What produced a stroke.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StrokeKind {
+    /// Drawn with the pen using the brush registered under this name
+    Pen { brush: String },
+    /// Stamped with a finger
+    Touch(TouchMode),
+}
+
+#[derive(Clone, Debug)]
+pub struct Stroke {
+    pub kind: StrokeKind,
+    pub style: StrokeStyle,
+    pub points: Vec<StrokePoint>,
+}
+
+impl Stroke {
+    /// The recorded points as brush samples, timed from `start`.
+    pub fn samples(&self, start: Instant) -> impl Iterator<Item = PenSample> + '_ {
+        self.points.iter().map(move |point| PenSample {
+            position: point.position,
+            pressure: point.pressure,
+            tilt: point.tilt,
+            time: start + point.time,
+        })
+    }
+}
+
+/// Every stroke drawn on the canvas, in order, so the drawing can be rendered
+/// again independently of the framebuffer.
+///
+/// Pen and finger strokes are recorded separately so both can be drawn at the
+/// same time. Edits made to the pixels directly, like blurring or inverting the
+/// canvas, aren't part of the document.
+#[derive(Clone, Debug, Default)]
+pub struct Document {
+    pub strokes: Vec<Stroke>,
+    /// Index and start time of the pen stroke still being recorded
+    pen: Option<(usize, Instant)>,
+    /// Index and start time of the finger stroke still being recorded
+    touch: Option<(usize, Instant)>,
+}
+
+impl Document {
+    pub fn new() -> Self {
+        Document::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strokes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.strokes.clear();
+        self.pen = None;
+        self.touch = None;
+    }
+
+    /// Starts recording a pen stroke with its first sample.
+    pub fn begin_stroke(&mut self, brush: &str, style: StrokeStyle, sample: PenSample) {
+        let kind = StrokeKind::Pen {
+            brush: brush.to_owned(),
+        };
+        self.pen = Some((self.strokes.len(), sample.time));
+        self.push_stroke(kind, style, sample);
+    }
+
+    /// Adds a sample to the pen stroke being recorded, if there is one.
+    pub fn add_point(&mut self, sample: PenSample) {
+        if let Some(open) = self.pen {
+            self.push_point(open, sample);
+        }
+    }
+
+    pub fn end_stroke(&mut self) {
+        self.pen = None;
+    }
+
+    /// Records a finger stamp, starting a new stroke when the finger has just
+    /// touched down or the touch mode changed since the last stamp.
+    pub fn stamp(&mut self, mode: TouchMode, style: StrokeStyle, sample: PenSample, press: bool) {
+        let open = match self.touch {
+            Some((index, start))
+                if !press
+                    && self.strokes.get(index).map(|stroke| &stroke.kind)
+                        == Some(&StrokeKind::Touch(mode)) =>
+            {
+                (index, start)
+            }
+            _ => {
+                self.touch = Some((self.strokes.len(), sample.time));
+                self.push_stroke(StrokeKind::Touch(mode), style, sample);
+                return;
+            }
+        };
+        self.push_point(open, sample);
+    }
+
+    fn push_stroke(&mut self, kind: StrokeKind, style: StrokeStyle, sample: PenSample) {
+        self.strokes.push(Stroke {
+            kind,
+            style,
+            points: vec![StrokePoint {
+                position: sample.position,
+                pressure: sample.pressure,
+                tilt: sample.tilt,
+                time: Duration::from_secs(0),
+            }],
+        });
+    }
+
+    fn push_point(&mut self, (index, start): (usize, Instant), sample: PenSample) {
+        if let Some(stroke) = self.strokes.get_mut(index) {
+            stroke.points.push(StrokePoint {
+                position: sample.position,
+                pressure: sample.pressure,
+                tilt: sample.tilt,
+                time: sample.time.saturating_duration_since(start),
+            });
+        }
+    }
+
+    /// Writes the strokes as text, each one a line followed by a line per point:
+    ///
+    /// ```text
+    /// stroke pen simple black 4 2 0.5 0 720 1404 1080 bezier 0.1 0.4 0.6 0.9
+    /// point 702.5 1204.25 1830 3 -4 0
+    /// point 705 1210.5 1902 3 -4 7500
+    /// stroke touch Circles black 0 1 0 0 720 1404 1080 linear
+    /// point 650 1000 0 0 0 0
+    /// ```
+    ///
+    /// A stroke has its brush or touch mode, then the color, size, speed
+    /// response, clip region as left, top, width and height, and the pressure
+    /// curve. A point has its position, pressure, tilt and the microseconds
+    /// since the stroke started.
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        for stroke in self.strokes.iter() {
+            let kind = match stroke.kind {
+                StrokeKind::Pen { ref brush } => format!("pen {}", brush),
+                StrokeKind::Touch(mode) => format!("touch {}", mode),
+            };
+            let style = stroke.style;
+            writeln!(
+                out,
+                "stroke {} {} {} {} {} {} {} {} {} {}",
+                kind,
+                color_name(style.color),
+                style.size,
+                style.velocity.half_width_speed,
+                style.velocity.blend,
+                style.clip.left,
+                style.clip.top,
+                style.clip.width,
+                style.clip.height,
+                style.pressure_curve
+            )?;
+            for point in stroke.points.iter() {
+                writeln!(
+                    out,
+                    "point {} {} {} {} {} {}",
+                    point.position.x,
+                    point.position.y,
+                    point.pressure,
+                    point.tilt.x,
+                    point.tilt.y,
+                    point.time.as_micros()
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads back the strokes written by `write`.
+    pub fn read(text: &str) -> io::Result<Self> {
+        let mut document = Document::new();
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let parsed = match line.split_whitespace().next() {
+                Some("stroke") => match parse_stroke(line) {
+                    Some(stroke) => {
+                        document.strokes.push(stroke);
+                        true
+                    }
+                    None => false,
+                },
+                Some("point") => match (parse_point(line), document.strokes.last_mut()) {
+                    (Some(point), Some(stroke)) => {
+                        stroke.points.push(point);
+                        true
+                    }
+                    _ => false,
+                },
+                _ => false,
+            };
+            if !parsed {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Bad stroke on line {}", number + 1),
+                ));
+            }
+        }
+        Ok(document)
+    }
+
+    /// Draws every stroke again, feeding the recorded samples through fresh
+    /// brushes the same way the input handlers do.
+    pub fn render(&self, fb: &mut dyn FramebufferDraw, registry: &BrushRegistry) -> mxcfb_rect {
+        let mut rect = mxcfb_rect::invalid();
+        for stroke in self.strokes.iter() {
+            rect = rect.merge_rect(&Document::render_stroke(fb, registry, stroke));
+        }
+        rect
+    }
+
+    fn render_stroke(
+        fb: &mut dyn FramebufferDraw,
+        registry: &BrushRegistry,
+        stroke: &Stroke,
+    ) -> mxcfb_rect {
+        let mut rect = mxcfb_rect::invalid();
+        let brush = match stroke.kind {
+            StrokeKind::Touch(mode) => {
+                for point in stroke.points.iter() {
+                    if let Some(stamp) = mode.draw_stamp(fb, point.position) {
+                        rect = rect.merge_rect(&stamp);
+                    }
+                }
+                return rect;
+            }
+            StrokeKind::Pen { ref brush } => registry.create(brush),
+        };
+        let mut brush = match brush {
+            Some(brush) => brush,
+            None => return rect,
+        };
+
+        let start = Instant::now();
+        let mut history: VecDeque<PenSample> = VecDeque::new();
+        let mut frame = 0;
+        for sample in stroke.samples(start) {
+            history.push_back(sample);
+            rect = rect.merge_rect(&if history.len() == 1 {
+                brush.begin_stroke(fb, sample, stroke.style)
+            } else {
+                brush.stroke_to(fb, &history, stroke.style)
+            });
+            while history.len() >= 3 {
+                history.pop_front();
+            }
+
+            // Step animated brushes as many frames as passed while drawing
+            let elapsed = sample.time.duration_since(start);
+            let until = (elapsed.as_secs_f32() / TICK_INTERVAL.as_secs_f32()) as u32;
+            while frame < until && brush.is_animating() {
+                rect = rect.merge_rect(&brush.tick(fb));
+                frame += 1;
+            }
+        }
+        rect = rect.merge_rect(&brush.end_stroke(fb));
+        // And let them settle after the pen lifts, like the app keeps stepping them
+        while brush.is_animating() {
+            rect = rect.merge_rect(&brush.tick(fb));
+        }
+        rect
+    }
+}
+
+fn color_name(c: color) -> String {
+    match c {
+        color::BLACK => "black".to_owned(),
+        color::WHITE => "white".to_owned(),
+        c => {
+            let [r, g, b] = c.to_rgb8();
+            format!("#{:02x}{:02x}{:02x}", r, g, b)
+        }
+    }
+}
+
+fn parse_color(name: &str) -> Option<color> {
+    match name {
+        "black" => Some(color::BLACK),
+        "white" => Some(color::WHITE),
+        _ => {
+            let hex = name.strip_prefix('#').filter(|hex| hex.len() == 6)?;
+            let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+            Some(color::RGB(channel(0)?, channel(2)?, channel(4)?))
+        }
+    }
+}
+
+fn parse_stroke(line: &str) -> Option<Stroke> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (kind, style) = match fields[1..] {
+        ["pen", brush, ref style @ ..] => (
+            StrokeKind::Pen {
+                brush: brush.to_owned(),
+            },
+            style,
+        ),
+        ["touch", mode, ref style @ ..] => (StrokeKind::Touch(mode.parse().ok()?), style),
+        _ => return None,
+    };
+    match style {
+        [color, size, speed, blend, left, top, width, height, ref curve @ ..] => Some(Stroke {
+            kind,
+            style: StrokeStyle {
+                color: parse_color(color)?,
+                size: size.parse().ok()?,
+                pressure_curve: curve.join(" ").parse().ok()?,
+                velocity: VelocityResponse {
+                    half_width_speed: speed.parse().ok()?,
+                    blend: blend.parse().ok()?,
+                },
+                clip: mxcfb_rect {
+                    left: left.parse().ok()?,
+                    top: top.parse().ok()?,
+                    width: width.parse().ok()?,
+                    height: height.parse().ok()?,
+                },
+            },
+            points: Vec::new(),
+        }),
+        _ => None,
+    }
+}
+
+fn parse_point(line: &str) -> Option<StrokePoint> {
+    match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["point", x, y, pressure, tilt_x, tilt_y, time] => Some(StrokePoint {
+            position: cgmath::Point2::new(x.parse().ok()?, y.parse().ok()?),
+            pressure: pressure.parse().ok()?,
+            tilt: cgmath::vec2(tilt_x.parse().ok()?, tilt_y.parse().ok()?),
+            time: Duration::from_micros(time.parse().ok()?),
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::HeadlessFramebuffer;
+    use crate::pressure::PressureCurve;
+    use crate::Session;
+    use libremarkable::framebuffer::FramebufferIO;
+    use libremarkable::input::{WacomEvent, WacomPen};
+
+    const REGION: mxcfb_rect = mxcfb_rect {
+        top: 0,
+        left: 0,
+        width: 200,
+        height: 120,
+    };
+
+    /// Where the pen is after `i` samples of a zigzag across the region.
+    fn position(i: u32) -> cgmath::Point2<f32> {
+        let x = 20.0 + i as f32 * 8.0;
+        let y = 40.0 + (i % 2) as f32 * 30.0;
+        cgmath::Point2::new(x, y)
+    }
+
+    fn sample(start: Instant, i: u32) -> PenSample {
+        PenSample {
+            position: position(i),
+            pressure: 1000 + i as i32 * 100,
+            tilt: cgmath::vec2(0, 0),
+            time: start + Duration::from_millis(i as u64 * 10),
+        }
+    }
+
+    fn blank() -> Vec<u8> {
+        let fb = HeadlessFramebuffer::new(REGION.width, REGION.height);
+        fb.dump_region(REGION).unwrap()
+    }
+
+    fn render(document: &Document) -> Vec<u8> {
+        let mut fb = HeadlessFramebuffer::new(REGION.width, REGION.height);
+        document.render(&mut fb, &BrushRegistry::default());
+        fb.dump_region(REGION).unwrap()
+    }
+
+    #[test]
+    fn renders_the_same_twice() {
+        let registry = BrushRegistry::default();
+        let style = StrokeStyle {
+            color: color::BLACK,
+            size: 4,
+            pressure_curve: PressureCurve::default(),
+            velocity: VelocityResponse::THINNING,
+            clip: REGION,
+        };
+        let start = Instant::now();
+        let mut document = Document::new();
+        // Every brush, including the random and animated ones
+        for name in registry.names() {
+            document.begin_stroke(name, style, sample(start, 0));
+            for i in 1..20 {
+                document.add_point(sample(start, i));
+            }
+            document.end_stroke();
+        }
+
+        let first = render(&document);
+        assert_ne!(first, blank());
+        assert_eq!(render(&document), first);
+    }
+
+    #[test]
+    fn renders_like_the_live_input() {
+        let mut session = Session::new(REGION);
+        session.predict_tail = false;
+        assert!(session.select_brush("simple"));
+        let mut fb = HeadlessFramebuffer::new(REGION.width, REGION.height);
+        session.reset(&fb).unwrap();
+
+        let start = Instant::now();
+        for i in 0..20 {
+            let sample = sample(start, i);
+            session.wacom_input(
+                &mut fb,
+                WacomEvent::Draw {
+                    position: sample.position,
+                    pressure: sample.pressure as u16,
+                    tilt: cgmath::vec2(0, 0),
+                },
+                sample.time,
+            );
+        }
+        session.wacom_input(
+            &mut fb,
+            WacomEvent::InstrumentChange {
+                pen: WacomPen::Touch,
+                state: false,
+            },
+            start + Duration::from_millis(200),
+        );
+
+        let live = fb.dump_region(REGION).unwrap();
+        assert_eq!(session.document().strokes.len(), 1);
+        assert_ne!(live, blank());
+        assert_eq!(render(session.document()), live);
+    }
+}
@@ -0,0 +1,289 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::document::Document;
+
+/// Identifies a saved drawing, followed by the format version.
+const MAGIC: &[u8; 4] = b"HRMZ";
+const FORMAT_VERSION: u16 = 1;
+const EXTENSION: &str = "hrmz";
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Raw pixels of a canvas region as dumped from the framebuffer, along with the
+/// strokes drawn on it.
+pub struct Drawing {
+    pub width: u32,
+    pub height: u32,
+    pub bytes_per_pixel: u8,
+    pub pixels: Vec<u8>,
+    pub document: Document,
+}
+
+impl Drawing {
+    pub fn new(width: u32, height: u32, pixels: Vec<u8>, document: Document) -> Self {
+        let bytes_per_pixel = (pixels.len() / (width * height).max(1) as usize) as u8;
+        Drawing {
+            width,
+            height,
+            bytes_per_pixel,
+            pixels,
+            document,
+        }
+    }
+
+    /// Reads a drawing written by `save`.
+    ///
+    /// The file starts with a small header, all little endian:
+    ///
+    /// | bytes | field                |
+    /// |-------|----------------------|
+    /// | 4     | `HRMZ`               |
+    /// | 2     | format version       |
+    /// | 4     | width in pixels      |
+    /// | 4     | height in pixels     |
+    /// | 1     | bytes per pixel      |
+    ///
+    /// followed by zstd compressed data: the pixels row by row, then the strokes
+    /// in the text form of `Document::write`.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut file = fs::File::open(path)?;
+        let mut header = [0u8; 15];
+        file.read_exact(&mut header)?;
+        if &header[0..4] != MAGIC {
+            return Err(invalid_data("Not a saved drawing"));
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != FORMAT_VERSION {
+            return Err(invalid_data("Unsupported drawing format version"));
+        }
+        let width = u32::from_le_bytes([header[6], header[7], header[8], header[9]]);
+        let height = u32::from_le_bytes([header[10], header[11], header[12], header[13]]);
+        let bytes_per_pixel = header[14];
+
+        let mut pixels = zstd::decode_all(file)?;
+        let size = width as usize * height as usize * bytes_per_pixel as usize;
+        if pixels.len() < size {
+            return Err(invalid_data("Drawing is truncated"));
+        }
+        let strokes = pixels.split_off(size);
+        let strokes = std::str::from_utf8(&strokes)
+            .map_err(|_| invalid_data("Strokes of the drawing aren't text"))?;
+        Ok(Drawing {
+            width,
+            height,
+            bytes_per_pixel,
+            pixels,
+            document: Document::read(strokes)?,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write next to the destination first so a crash never leaves half a file
+        let partial = path.with_extension("partial");
+        let mut file = fs::File::create(&partial)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        file.write_all(&self.width.to_le_bytes())?;
+        file.write_all(&self.height.to_le_bytes())?;
+        file.write_all(&[self.bytes_per_pixel])?;
+        let mut encoder = zstd::Encoder::new(&mut file, COMPRESSION_LEVEL)?;
+        encoder.write_all(&self.pixels)?;
+        self.document.write(&mut encoder)?;
+        encoder.finish()?;
+        file.sync_all()?;
+        fs::rename(partial, path)
+    }
+}
+
+/// Path of the drawing called `name` within `dir`.
+pub fn path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(name).with_extension(EXTENSION)
+}
+
+/// `name`, or if a drawing in `dir` already has it, `name` numbered with the
+/// first free count from 2 up.
+pub fn unused_name(dir: &Path, name: &str) -> String {
+    let mut unused = name.to_owned();
+    let mut count = 1;
+    while path(dir, &unused).exists() {
+        count += 1;
+        unused = format!("{}-{}", name, count);
+    }
+    unused
+}
+
+/// Names of the drawings saved in `dir`, sorted in reverse so that timestamped
+/// names list the newest first.
+pub fn list(dir: &Path) -> io::Result<Vec<String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(EXTENSION) {
+            continue;
+        }
+        if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+            names.push(name.to_owned());
+        }
+    }
+    names.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(names)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brushes::{PenSample, StrokeStyle};
+    use crate::document::StrokeKind;
+    use crate::pressure::{PressureCurve, VelocityResponse};
+    use crate::touch::TouchMode;
+    use libremarkable::framebuffer::cgmath;
+    use libremarkable::framebuffer::common::*;
+    use std::time::{Duration, Instant};
+
+    fn temp_dir(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("harmonizers-{}-{}", test, std::process::id()))
+    }
+
+    fn sample(x: f32, y: f32, time: Instant) -> PenSample {
+        PenSample {
+            position: cgmath::Point2::new(x, y),
+            pressure: 1830,
+            tilt: cgmath::vec2(3, -4),
+            time,
+        }
+    }
+
+    fn document() -> Document {
+        let style = StrokeStyle {
+            color: color::WHITE,
+            size: 6,
+            pressure_curve: PressureCurve::Bezier {
+                x1: 0.1,
+                y1: 0.4,
+                x2: 0.6,
+                y2: 0.9,
+            },
+            velocity: VelocityResponse::THINNING,
+            clip: mxcfb_rect {
+                top: 720,
+                left: 0,
+                width: 1404,
+                height: 1080,
+            },
+        };
+        let start = Instant::now();
+        let mut document = Document::new();
+        document.begin_stroke("ribbon", style, sample(702.5, 1204.25, start));
+        document.add_point(sample(705.0, 1210.125, start + Duration::from_micros(7500)));
+        document.end_stroke();
+        document.stamp(
+            TouchMode::FillDiamonds,
+            StrokeStyle {
+                color: color::BLACK,
+                pressure_curve: PressureCurve::Linear,
+                ..style
+            },
+            sample(650.0, 1000.0, start),
+            true,
+        );
+        document
+    }
+
+    #[test]
+    fn round_trips_pixels_and_strokes() {
+        let dir = temp_dir("round-trip");
+        let pixels: Vec<u8> = (0..4 * 3 * 2).map(|i| i as u8).collect();
+        let drawing = Drawing::new(4, 3, pixels.clone(), document());
+        let path = path(&dir, "drawing");
+        drawing.save(&path).unwrap();
+        let loaded = Drawing::load(&path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!((loaded.width, loaded.height), (4, 3));
+        assert_eq!(loaded.bytes_per_pixel, 2);
+        assert_eq!(loaded.pixels, pixels);
+        let strokes = &loaded.document.strokes;
+        assert_eq!(strokes.len(), 2);
+        for (loaded, saved) in strokes.iter().zip(drawing.document.strokes.iter()) {
+            assert_eq!(loaded.kind, saved.kind);
+            assert_eq!(loaded.points, saved.points);
+            assert_eq!(loaded.style.color.to_rgb8(), saved.style.color.to_rgb8());
+            assert_eq!(loaded.style.size, saved.style.size);
+            assert_eq!(loaded.style.pressure_curve, saved.style.pressure_curve);
+            assert_eq!(loaded.style.velocity, saved.style.velocity);
+            assert_eq!(
+                (loaded.style.clip.left, loaded.style.clip.top),
+                (saved.style.clip.left, saved.style.clip.top)
+            );
+        }
+        assert_eq!(
+            strokes[0].kind,
+            StrokeKind::Pen {
+                brush: "ribbon".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let dir = temp_dir("headers");
+        fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, header: &[u8]| {
+            let path = path(&dir, name);
+            let mut file = fs::File::create(&path).unwrap();
+            file.write_all(header).unwrap();
+            zstd::stream::copy_encode(&[0u8; 2][..], &mut file, COMPRESSION_LEVEL).unwrap();
+            path
+        };
+        let header = |magic: &[u8; 4], version: u16| {
+            let mut header = magic.to_vec();
+            header.extend_from_slice(&version.to_le_bytes());
+            header.extend_from_slice(&1u32.to_le_bytes());
+            header.extend_from_slice(&1u32.to_le_bytes());
+            header.push(2);
+            header
+        };
+
+        let results = [
+            Drawing::load(&write("magic", &header(b"PNG\0", FORMAT_VERSION))),
+            Drawing::load(&write("version", &header(MAGIC, FORMAT_VERSION + 1))),
+            {
+                let short = path(&dir, "short");
+                fs::write(&short, &header(MAGIC, FORMAT_VERSION)[..10]).unwrap();
+                Drawing::load(&short)
+            },
+        ];
+        fs::remove_dir_all(&dir).unwrap();
+
+        let [magic, version, short] = results;
+        assert_eq!(magic.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(version.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(short.err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn numbers_taken_names() {
+        let dir = temp_dir("names");
+        assert_eq!(unused_name(&dir, "drawing"), "drawing");
+        Drawing::new(1, 1, vec![0, 0], Document::new())
+            .save(&path(&dir, "drawing"))
+            .unwrap();
+        let second = unused_name(&dir, "drawing");
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(second, "drawing-2");
+    }
+}
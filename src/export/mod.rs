@@ -0,0 +1,177 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::cgmath::InnerSpace;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::storage;
+use libremarkable::image::{self, ColorType, DynamicImage, GrayImage};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+mod pdf;
+mod svg;
+
+pub use self::pdf::write_pdf;
+pub use self::svg::write_svg;
+
+/// Converts canvas pixels as dumped from the framebuffer to grayscale, or
+/// `None` if there aren't exactly `width * height` of them.
+pub fn grayscale_from_canvas(width: u32, height: u32, pixels: &[u8]) -> Option<GrayImage> {
+    storage::rgbimage_from_u8_slice(width, height, pixels)
+        .map(|rgb| DynamicImage::ImageRgb8(rgb).to_luma8())
+}
+
+/// Encodes canvas pixels as dumped from the framebuffer as a grayscale PNG.
+pub fn png_from_canvas(width: u32, height: u32, pixels: &[u8]) -> io::Result<Vec<u8>> {
+    let gray = grayscale_from_canvas(width, height, pixels).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Pixels don't match the canvas size",
+        )
+    })?;
+    let mut png = Vec::new();
+    image::png::PngEncoder::new(&mut png)
+        .encode(&gray, width, height, ColorType::L8)
+        .map_err(io::Error::other)?;
+    Ok(png)
+}
+
+pub fn write_png(path: &Path, width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
+    let png = png_from_canvas(width, height, pixels)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, png)
+}
+
+/// Points along each side of the outline of a variable width bezier.
+const BEZIER_SAMPLES: usize = 16;
+
+/// Left and right edge of a stroke outline, both running from start to end.
+type Outline = (Vec<cgmath::Point2<f32>>, Vec<cgmath::Point2<f32>>);
+
+/// Outline of a quadratic bezier whose width is interpolated between the given
+/// diameters. `None` if the curve has no length.
+fn bezier_outline(
+    startpt: (cgmath::Point2<f32>, f32),
+    ctrlpt: (cgmath::Point2<f32>, f32),
+    endpt: (cgmath::Point2<f32>, f32),
+) -> Option<Outline> {
+    let (p0, p1, p2) = (startpt.0, ctrlpt.0, endpt.0);
+    // Direction to fall back on where the curve stops moving for an instant
+    let chord = if (p2 - p0).magnitude2() > f32::EPSILON {
+        p2 - p0
+    } else {
+        p1 - p0
+    };
+    if chord.magnitude2() <= f32::EPSILON {
+        return None;
+    }
+
+    let mut direction = chord.normalize();
+    let mut left = Vec::with_capacity(BEZIER_SAMPLES + 1);
+    let mut right = Vec::with_capacity(BEZIER_SAMPLES + 1);
+    for i in 0..=BEZIER_SAMPLES {
+        let t = i as f32 / BEZIER_SAMPLES as f32;
+        let u = 1.0 - t;
+        let position = p0 + (p1 - p0) * (2.0 * u * t) + (p2 - p0) * (t * t);
+        let tangent = (p1 - p0) * (2.0 * u) + (p2 - p1) * (2.0 * t);
+        if tangent.magnitude2() > f32::EPSILON {
+            direction = tangent.normalize();
+        }
+        let normal = cgmath::vec2(-direction.y, direction.x);
+        let radius = (startpt.1 * u * u + ctrlpt.1 * 2.0 * u * t + endpt.1 * t * t) / 2.0;
+        left.push(position + normal * radius);
+        right.push(position - normal * radius);
+    }
+    Some((left, right))
+}
+
+/// How a shape gets painted: filled, or outlined with a line of the given
+/// width.
+#[derive(Copy, Clone)]
+enum Paint {
+    Fill(color),
+    Stroke(color, f32),
+}
+
+impl Paint {
+    /// Paint for the framebuffer's shapes that are either filled or outlined a
+    /// pixel wide.
+    fn new(c: color, fill: bool) -> Self {
+        match fill {
+            true => Paint::Fill(c),
+            false => Paint::Stroke(c, 1.0),
+        }
+    }
+}
+
+/// Red, green and blue of `c`, from 0 to 1.
+fn rgb(c: color) -> [f32; 3] {
+    c.to_rgb8().map(|v| v as f32 / 255.0)
+}
+
+/// Screen rect covering `points` grown by `pad` on every side.
+fn bounds(points: &[cgmath::Point2<f32>], pad: f32) -> mxcfb_rect {
+    let mut min = cgmath::Point2::new(f32::MAX, f32::MAX);
+    let mut max = cgmath::Point2::new(f32::MIN, f32::MIN);
+    for p in points.iter() {
+        min = cgmath::Point2::new(min.x.min(p.x), min.y.min(p.y));
+        max = cgmath::Point2::new(max.x.max(p.x), max.y.max(p.y));
+    }
+    let left = (min.x - pad).max(0.0).floor();
+    let top = (min.y - pad).max(0.0).floor();
+    mxcfb_rect {
+        top: top as u32,
+        left: left as u32,
+        width: ((max.x + pad).ceil() - left).max(1.0) as u32,
+        height: ((max.y + pad).ceil() - top).max(1.0) as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: [u8; 2] = [0xff, 0xff];
+    const BLACK: [u8; 2] = [0x00, 0x00];
+
+    /// A white canvas in the framebuffer's native RGB565 with a black diagonal.
+    fn synthetic_canvas(width: u32, height: u32) -> Vec<u8> {
+        let mut pixels = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                pixels.extend_from_slice(if x == y { &BLACK } else { &WHITE });
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn exports_grayscale_png() {
+        let png = png_from_canvas(8, 6, &synthetic_canvas(8, 6)).unwrap();
+        let decoded = image::load_from_memory(&png).unwrap();
+        assert_eq!(decoded.color(), ColorType::L8);
+
+        let gray = decoded.to_luma8();
+        assert_eq!(gray.dimensions(), (8, 6));
+        let expected: Vec<u8> = (0..6)
+            .flat_map(|y| (0..8).map(move |x| if x == y { 0 } else { 255 }))
+            .collect();
+        assert_eq!(gray.into_raw(), expected);
+    }
+
+    #[test]
+    fn export_is_reproducible() {
+        let pixels = synthetic_canvas(16, 16);
+        assert_eq!(
+            png_from_canvas(16, 16, &pixels).unwrap(),
+            png_from_canvas(16, 16, &pixels).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_size() {
+        assert!(png_from_canvas(16, 16, &synthetic_canvas(8, 8)).is_err());
+    }
+}
@@ -0,0 +1,564 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+use libremarkable::image::RgbImage;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::{bezier_outline, bounds, grayscale_from_canvas, rgb, Paint};
+use crate::brushes::BrushRegistry;
+use crate::document::Document;
+
+/// The reMarkable screen has 226 pixels per inch, PDF pages 72 points.
+const POINTS_PER_PIXEL: f32 = 72.0 / 226.0;
+
+// Distance of the control points approximating a quarter circle with a cubic
+// bezier, relative to the radius
+const KAPPA: f32 = 0.552_284_8;
+
+/// Framebuffer stand-in that collects what is drawn to it as PDF path operators
+/// in screen coordinates.
+///
+/// Like `SvgCanvas` it turns recorded strokes into vector shapes, skipping
+/// images and text.
+pub struct PdfCanvas {
+    ops: String,
+}
+
+impl PdfCanvas {
+    pub fn new() -> Self {
+        PdfCanvas { ops: String::new() }
+    }
+
+    /// Sets up the color and line width of `paint`, returning the operator
+    /// that paints a path with it.
+    fn paint(&mut self, paint: Paint) -> &'static str {
+        let [r, g, b] = match paint {
+            Paint::Fill(c) | Paint::Stroke(c, _) => rgb(c),
+        };
+        match paint {
+            Paint::Fill(_) => {
+                self.ops
+                    .push_str(&format!("{:.3} {:.3} {:.3} rg\n", r, g, b));
+                "f"
+            }
+            Paint::Stroke(_, width) => {
+                self.ops.push_str(&format!(
+                    "{:.3} {:.3} {:.3} RG {:.2} w\n",
+                    r,
+                    g,
+                    b,
+                    width.max(1.0)
+                ));
+                "S"
+            }
+        }
+    }
+
+    fn polygon_path(&mut self, points: &[cgmath::Point2<f32>]) {
+        for (i, p) in points.iter().enumerate() {
+            let op = if i == 0 { "m" } else { "l" };
+            self.ops
+                .push_str(&format!("{:.2} {:.2} {}\n", p.x, p.y, op));
+        }
+        self.ops.push_str("h\n");
+    }
+
+    fn circle_path(&mut self, center: cgmath::Point2<f32>, radius: f32) {
+        let (x, y, r, k) = (center.x, center.y, radius, radius * KAPPA);
+        self.ops.push_str(&format!("{:.2} {:.2} m\n", x + r, y));
+        for (c1, c2, end) in [
+            ((x + r, y + k), (x + k, y + r), (x, y + r)),
+            ((x - k, y + r), (x - r, y + k), (x - r, y)),
+            ((x - r, y - k), (x - k, y - r), (x, y - r)),
+            ((x + k, y - r), (x + r, y - k), (x + r, y)),
+        ] {
+            self.ops.push_str(&format!(
+                "{:.2} {:.2} {:.2} {:.2} {:.2} {:.2} c\n",
+                c1.0, c1.1, c2.0, c2.1, end.0, end.1
+            ));
+        }
+        self.ops.push_str("h\n");
+    }
+
+    fn circle(&mut self, pos: cgmath::Point2<i32>, rad: u32, c: color, fill: bool) -> mxcfb_rect {
+        let center = pos.cast().unwrap();
+        let op = self.paint(Paint::new(c, fill));
+        self.circle_path(center, rad as f32);
+        self.ops.push_str(&format!("{}\n", op));
+        bounds(&[center], rad as f32 + 1.0)
+    }
+}
+
+impl Default for PdfCanvas {
+    fn default() -> Self {
+        PdfCanvas::new()
+    }
+}
+
+impl FramebufferDraw for PdfCanvas {
+    fn draw_image(&mut self, _img: &RgbImage, _pos: cgmath::Point2<i32>) -> mxcfb_rect {
+        mxcfb_rect::invalid()
+    }
+
+    fn draw_line(
+        &mut self,
+        start: cgmath::Point2<i32>,
+        end: cgmath::Point2<i32>,
+        width: u32,
+        v: color,
+    ) -> mxcfb_rect {
+        let op = self.paint(Paint::Stroke(v, width as f32));
+        self.ops.push_str(&format!(
+            "{} {} m {} {} l {}\n",
+            start.x, start.y, end.x, end.y, op
+        ));
+        bounds(
+            &[start.cast().unwrap(), end.cast().unwrap()],
+            width as f32 / 2.0,
+        )
+    }
+
+    fn draw_polygon(&mut self, points: &[cgmath::Point2<i32>], fill: bool, c: color) -> mxcfb_rect {
+        let points: Vec<cgmath::Point2<f32>> = points.iter().map(|p| p.cast().unwrap()).collect();
+        let op = self.paint(Paint::new(c, fill));
+        self.polygon_path(&points);
+        self.ops.push_str(&format!("{}\n", op));
+        bounds(&points, 1.0)
+    }
+
+    fn draw_circle(&mut self, pos: cgmath::Point2<i32>, rad: u32, c: color) -> mxcfb_rect {
+        self.circle(pos, rad, c, false)
+    }
+
+    fn fill_circle(&mut self, pos: cgmath::Point2<i32>, rad: u32, c: color) -> mxcfb_rect {
+        self.circle(pos, rad, c, true)
+    }
+
+    fn draw_bezier(
+        &mut self,
+        startpt: cgmath::Point2<f32>,
+        ctrlpt: cgmath::Point2<f32>,
+        endpt: cgmath::Point2<f32>,
+        width: f32,
+        _samples: i32,
+        v: color,
+    ) -> mxcfb_rect {
+        // PDF only has cubic beziers, which can represent any quadratic one
+        let c1 = startpt + (ctrlpt - startpt) * (2.0 / 3.0);
+        let c2 = endpt + (ctrlpt - endpt) * (2.0 / 3.0);
+        let op = self.paint(Paint::Stroke(v, width));
+        self.ops.push_str(&format!(
+            "{:.2} {:.2} m {:.2} {:.2} {:.2} {:.2} {:.2} {:.2} c {}\n",
+            startpt.x, startpt.y, c1.x, c1.y, c2.x, c2.y, endpt.x, endpt.y, op
+        ));
+        bounds(&[startpt, ctrlpt, endpt], width / 2.0)
+    }
+
+    fn draw_dynamic_bezier(
+        &mut self,
+        startpt: (cgmath::Point2<f32>, f32),
+        ctrlpt: (cgmath::Point2<f32>, f32),
+        endpt: (cgmath::Point2<f32>, f32),
+        _samples: i32,
+        v: color,
+    ) -> mxcfb_rect {
+        let max_width = startpt.1.max(ctrlpt.1).max(endpt.1);
+        let rect = bounds(&[startpt.0, ctrlpt.0, endpt.0], max_width / 2.0);
+        self.paint(Paint::Fill(v));
+        if let Some((left, right)) = bezier_outline(startpt, ctrlpt, endpt) {
+            let points: Vec<cgmath::Point2<f32>> =
+                left.into_iter().chain(right.into_iter().rev()).collect();
+            self.polygon_path(&points);
+            self.ops.push_str("f\n");
+            // Round caps, filled on their own as their winding may oppose the outline's
+            self.circle_path(endpt.0, endpt.1 / 2.0);
+            self.ops.push_str("f\n");
+        }
+        self.circle_path(startpt.0, startpt.1 / 2.0);
+        self.ops.push_str("f\n");
+        rect
+    }
+
+    fn draw_text(
+        &mut self,
+        _pos: cgmath::Point2<f32>,
+        _text: &str,
+        _size: f32,
+        _col: color,
+        _dryrun: bool,
+    ) -> mxcfb_rect {
+        mxcfb_rect::invalid()
+    }
+
+    fn draw_rect(
+        &mut self,
+        pos: cgmath::Point2<i32>,
+        size: cgmath::Vector2<u32>,
+        border_px: u32,
+        c: color,
+    ) {
+        let op = self.paint(Paint::Stroke(c, border_px as f32));
+        self.ops.push_str(&format!(
+            "{} {} {} {} re {}\n",
+            pos.x, pos.y, size.x, size.y, op
+        ));
+    }
+
+    fn fill_rect(&mut self, pos: cgmath::Point2<i32>, size: cgmath::Vector2<u32>, c: color) {
+        let op = self.paint(Paint::Fill(c));
+        self.ops.push_str(&format!(
+            "{} {} {} {} re {}\n",
+            pos.x, pos.y, size.x, size.y, op
+        ));
+    }
+
+    fn clear(&mut self) {
+        self.ops.clear();
+    }
+}
+
+/// Writes the canvas as a one page PDF sized to the canvas region.
+///
+/// The page holds the canvas pixels in grayscale, run-length encoded so
+/// nothing is lost. When `strokes` are given and not empty, they are drawn
+/// as vector paths on top, right over their pixels, so the strokes stay sharp
+/// when zoomed in while anything else on the canvas still shows.
+pub fn pdf_from_canvas(
+    region: mxcfb_rect,
+    pixels: &[u8],
+    strokes: Option<(&Document, &BrushRegistry)>,
+) -> io::Result<Vec<u8>> {
+    let gray = grayscale_from_canvas(region.width, region.height, pixels).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Pixels don't match the canvas size",
+        )
+    })?;
+
+    let width = region.width as f32 * POINTS_PER_PIXEL;
+    let height = region.height as f32 * POINTS_PER_PIXEL;
+    let mut content = format!("q {:.2} 0 0 {:.2} 0 0 cm /Canvas Do Q\n", width, height);
+    if let Some((document, registry)) = strokes.filter(|(document, _)| !document.is_empty()) {
+        let mut canvas = PdfCanvas::new();
+        document.render(&mut canvas, registry);
+        // Flip to screen coordinates with the canvas' top left corner at the origin
+        content.push_str(&format!(
+            "q {s:.6} 0 0 {n:.6} {x:.2} {y:.2} cm 1 J 1 j\n",
+            s = POINTS_PER_PIXEL,
+            n = -POINTS_PER_PIXEL,
+            x = -(region.left as f32) * POINTS_PER_PIXEL,
+            y = (region.top + region.height) as f32 * POINTS_PER_PIXEL
+        ));
+        content.push_str(&canvas.ops);
+        content.push_str("Q\n");
+    }
+
+    // 1 catalog, 2 page tree, 3 image, 4 content stream, 5 page
+    let mut pdf = PdfFile::new();
+    pdf.object(b"<< /Type /Catalog /Pages 2 0 R >>");
+    pdf.object(b"<< /Type /Pages /Kids [5 0 R] /Count 1 >>");
+    pdf.stream(
+        &format!(
+            "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /DeviceGray /BitsPerComponent 8 /Filter /RunLengthDecode",
+            region.width, region.height
+        ),
+        &run_length_encode(gray.as_raw()),
+    );
+    pdf.stream("", content.as_bytes());
+    pdf.object(
+        format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.2} {:.2}] /Resources << /XObject << /Canvas 3 0 R >> >> /Contents 4 0 R >>",
+            width, height
+        )
+        .as_bytes(),
+    );
+    Ok(pdf.finish(1))
+}
+
+pub fn write_pdf(
+    path: &Path,
+    region: mxcfb_rect,
+    pixels: &[u8],
+    strokes: Option<(&Document, &BrushRegistry)>,
+) -> io::Result<()> {
+    let pdf = pdf_from_canvas(region, pixels, strokes)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, pdf)
+}
+
+/// A PDF file being written object by object, numbered from 1 in the order they
+/// are added.
+struct PdfFile {
+    buffer: Vec<u8>,
+    offsets: Vec<usize>,
+}
+
+impl PdfFile {
+    fn new() -> Self {
+        let mut buffer = b"%PDF-1.4\n".to_vec();
+        // Binary comment so transfers treat the file as binary
+        buffer.extend_from_slice(b"%\xe2\xe3\xcf\xd3\n");
+        PdfFile {
+            buffer,
+            offsets: Vec::new(),
+        }
+    }
+
+    fn begin_object(&mut self) {
+        self.offsets.push(self.buffer.len());
+        let header = format!("{} 0 obj\n", self.offsets.len());
+        self.buffer.extend_from_slice(header.as_bytes());
+    }
+
+    fn object(&mut self, body: &[u8]) {
+        self.begin_object();
+        self.buffer.extend_from_slice(body);
+        self.buffer.extend_from_slice(b"\nendobj\n");
+    }
+
+    fn stream(&mut self, dictionary: &str, data: &[u8]) {
+        self.begin_object();
+        let header = match dictionary {
+            "" => format!("<< /Length {} >>\nstream\n", data.len()),
+            _ => format!("<< {} /Length {} >>\nstream\n", dictionary, data.len()),
+        };
+        self.buffer.extend_from_slice(header.as_bytes());
+        self.buffer.extend_from_slice(data);
+        self.buffer.extend_from_slice(b"\nendstream\nendobj\n");
+    }
+
+    fn finish(mut self, root: usize) -> Vec<u8> {
+        let xref = self.buffer.len();
+        let mut table = format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len() + 1);
+        for offset in self.offsets.iter() {
+            table.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+        table.push_str(&format!(
+            "trailer\n<< /Size {} /Root {} 0 R >>\nstartxref\n{}\n%%EOF\n",
+            self.offsets.len() + 1,
+            root,
+            xref
+        ));
+        self.buffer.extend_from_slice(table.as_bytes());
+        self.buffer
+    }
+}
+
+/// Compresses `data` for the PDF `RunLengthDecode` filter: a length byte below
+/// 128 is followed by that many plus one literal bytes, one above 128 by a byte
+/// repeated 257 minus that many times, and 128 ends the data.
+fn run_length_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+    while i < data.len() {
+        let mut run = 1;
+        while i + run < data.len() && run < 128 && data[i + run] == data[i] {
+            run += 1;
+        }
+        if run > 1 {
+            for chunk in data[literal_start..i].chunks(128) {
+                encoded.push((chunk.len() - 1) as u8);
+                encoded.extend_from_slice(chunk);
+            }
+            encoded.push((257 - run) as u8);
+            encoded.push(data[i]);
+            i += run;
+            literal_start = i;
+        } else {
+            i += 1;
+        }
+    }
+    for chunk in data[literal_start..].chunks(128) {
+        encoded.push((chunk.len() - 1) as u8);
+        encoded.extend_from_slice(chunk);
+    }
+    encoded.push(128);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brushes::{PenSample, StrokeStyle};
+    use crate::pressure::{PressureCurve, VelocityResponse};
+    use crate::touch::TouchMode;
+    use std::time::Instant;
+
+    const REGION: mxcfb_rect = mxcfb_rect {
+        top: 720,
+        left: 0,
+        height: 1080,
+        width: 1404,
+    };
+
+    /// A white canvas in the framebuffer's native RGB565 with black stripes.
+    fn synthetic_canvas() -> Vec<u8> {
+        let mut pixels = Vec::new();
+        for y in 0..REGION.height {
+            for x in 0..REGION.width {
+                let black = (x / 7 + y / 5) % 3 == 0;
+                pixels.extend_from_slice(if black { &[0x00, 0x00] } else { &[0xff, 0xff] });
+            }
+        }
+        pixels
+    }
+
+    fn stamped_document() -> Document {
+        let mut document = Document::new();
+        let style = StrokeStyle {
+            color: color::BLACK,
+            size: 0,
+            pressure_curve: PressureCurve::Linear,
+            velocity: VelocityResponse::NONE,
+            clip: REGION,
+        };
+        let sample = PenSample {
+            position: cgmath::Point2::new(300.0, 1000.0),
+            pressure: 0,
+            tilt: cgmath::vec2(0, 0),
+            time: Instant::now(),
+        };
+        document.stamp(TouchMode::Circles, style, sample, true);
+        document
+    }
+
+    fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+        haystack[from..]
+            .windows(needle.len())
+            .position(|window| window == needle)
+            .map(|i| i + from)
+    }
+
+    /// Text between `start` and the next `end` after `from`.
+    fn between<'a>(pdf: &'a [u8], from: usize, start: &str, end: &str) -> &'a str {
+        let begin = find(pdf, start.as_bytes(), from).unwrap() + start.len();
+        let finish = find(pdf, end.as_bytes(), begin).unwrap();
+        std::str::from_utf8(&pdf[begin..finish]).unwrap().trim()
+    }
+
+    /// Stream data of the object numbered `number`.
+    fn stream_of(pdf: &[u8], offsets: &[usize], number: usize) -> Vec<u8> {
+        let offset = offsets[number - 1];
+        let length: usize = between(pdf, offset, "/Length", ">>").parse().unwrap();
+        let data = find(pdf, b"stream\n", offset).unwrap() + "stream\n".len();
+        assert_eq!(&pdf[data + length..data + length + 10], b"\nendstream");
+        pdf[data..data + length].to_vec()
+    }
+
+    fn run_length_decode(data: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        let mut i = 0;
+        loop {
+            let length = data[i] as usize;
+            match length {
+                128 => break,
+                0..=127 => {
+                    decoded.extend_from_slice(&data[i + 1..i + 2 + length]);
+                    i += 2 + length;
+                }
+                _ => {
+                    decoded.extend(std::iter::repeat_n(data[i + 1], 257 - length));
+                    i += 2;
+                }
+            }
+        }
+        assert_eq!(i, data.len() - 1, "data after end of run-length stream");
+        decoded
+    }
+
+    /// Offsets of every object from the cross-reference table, checking each
+    /// one points at its object.
+    fn parse_xref(pdf: &[u8]) -> Vec<usize> {
+        let tail = std::str::from_utf8(&pdf[pdf.len() - 32..]).unwrap();
+        let xref: usize = tail
+            .split("startxref")
+            .nth(1)
+            .unwrap()
+            .trim()
+            .trim_end_matches("%%EOF")
+            .trim()
+            .parse()
+            .unwrap();
+        let table = std::str::from_utf8(&pdf[xref..]).unwrap();
+        let mut lines = table.lines();
+        assert_eq!(lines.next(), Some("xref"));
+        let count: usize = lines.next().unwrap()[2..].parse().unwrap();
+        assert_eq!(lines.next(), Some("0000000000 65535 f "));
+        let offsets: Vec<usize> = (1..count)
+            .map(|_| lines.next().unwrap()[..10].parse().unwrap())
+            .collect();
+        for (i, offset) in offsets.iter().enumerate() {
+            let header = format!("{} 0 obj", i + 1);
+            assert!(pdf[*offset..].starts_with(header.as_bytes()));
+        }
+        assert_eq!(lines.next(), Some("trailer"));
+        assert_eq!(
+            lines.next(),
+            Some(format!("<< /Size {} /Root 1 0 R >>", count).as_str())
+        );
+        offsets
+    }
+
+    #[test]
+    fn run_length_round_trips() {
+        let mut data = vec![7u8; 300];
+        data.extend((0..=255).cycle().take(400));
+        data.extend([1, 1, 2, 3, 3, 3]);
+        assert_eq!(run_length_decode(&run_length_encode(&data)), data);
+        assert_eq!(run_length_encode(&[]), vec![128]);
+    }
+
+    #[test]
+    fn parses_back_pixels_and_strokes() {
+        let pixels = synthetic_canvas();
+        let registry = BrushRegistry::default();
+        let document = stamped_document();
+        let pdf = pdf_from_canvas(REGION, &pixels, Some((&document, &registry))).unwrap();
+        assert!(pdf.starts_with(b"%PDF-1.4\n"));
+
+        let offsets = parse_xref(&pdf);
+        assert_eq!(offsets.len(), 5);
+        assert_eq!(between(&pdf, offsets[1], "/Count", ">>"), "1");
+
+        // The page keeps the aspect of the canvas
+        let media_box: Vec<f32> = between(&pdf, offsets[4], "/MediaBox [", "]")
+            .split(' ')
+            .map(|n| n.parse().unwrap())
+            .collect();
+        let aspect = REGION.width as f32 / REGION.height as f32;
+        assert!((media_box[2] / media_box[3] - aspect).abs() < 1e-3);
+
+        // The image decodes to exactly the grayscale canvas
+        let image = stream_of(&pdf, &offsets, 3);
+        let gray = grayscale_from_canvas(REGION.width, REGION.height, &pixels).unwrap();
+        assert_eq!(run_length_decode(&image), gray.into_raw());
+
+        // The stamp comes out as a stroked circle of four curves, drawn over
+        // the image
+        let content = String::from_utf8(stream_of(&pdf, &offsets, 4)).unwrap();
+        let (below, vectors) = content.split_once("/Canvas Do Q\n").unwrap();
+        assert!(!below.contains(" c\n"));
+        assert_eq!(vectors.matches(" c\n").count(), 4);
+        assert!(vectors.trim_end().ends_with("S\nQ"));
+    }
+
+    #[test]
+    fn skips_vectors_without_strokes() {
+        let pdf = pdf_from_canvas(REGION, &synthetic_canvas(), None).unwrap();
+        let offsets = parse_xref(&pdf);
+        assert_eq!(offsets.len(), 5);
+        let content = String::from_utf8(stream_of(&pdf, &offsets, 4)).unwrap();
+        assert!(content.trim_end().ends_with("/Canvas Do Q"));
+
+        let empty = Document::new();
+        let registry = BrushRegistry::default();
+        let blank = pdf_from_canvas(REGION, &synthetic_canvas(), Some((&empty, &registry)));
+        assert_eq!(blank.unwrap(), pdf);
+    }
+}
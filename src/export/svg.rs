@@ -0,0 +1,383 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+use libremarkable::image::RgbImage;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::{bezier_outline, bounds, Paint};
+use crate::brushes::BrushRegistry;
+use crate::document::Document;
+
+/// Framebuffer stand-in that collects what is drawn to it as SVG elements.
+///
+/// Rendering a document into it turns the output of every brush and touch stamp
+/// into vector shapes: lines, polygons, circles, and outlined paths for
+/// variable width beziers. Erasing draws in white, so erased parts come out as
+/// white shapes on top. Images and text aren't part of a drawing and are skipped.
+pub struct SvgCanvas {
+    region: mxcfb_rect,
+    elements: Vec<String>,
+}
+
+impl SvgCanvas {
+    /// A canvas for shapes drawn in screen coordinates within `region`.
+    pub fn new(region: mxcfb_rect) -> Self {
+        SvgCanvas {
+            region,
+            elements: Vec::new(),
+        }
+    }
+
+    /// The SVG document, sized to the region with its top left corner moved to
+    /// the origin.
+    pub fn to_svg(&self) -> String {
+        let (width, height) = (self.region.width, self.region.height);
+        let mut svg = String::new();
+        svg.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        svg.push_str(&format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n",
+            width, height
+        ));
+        svg.push_str(&format!(
+            "<rect width=\"{0}\" height=\"{1}\" fill=\"#ffffff\"/>\n",
+            width, height
+        ));
+        svg.push_str(&format!(
+            "<g transform=\"translate(-{0} -{1})\">\n",
+            self.region.left, self.region.top
+        ));
+        for element in self.elements.iter() {
+            svg.push_str(element);
+            svg.push('\n');
+        }
+        svg.push_str("</g>\n</svg>\n");
+        svg
+    }
+
+    fn circle(&mut self, pos: cgmath::Point2<i32>, rad: u32, c: color, fill: bool) -> mxcfb_rect {
+        self.elements.push(format!(
+            "<circle cx=\"{}\" cy=\"{}\" r=\"{}\" {}/>",
+            pos.x,
+            pos.y,
+            rad,
+            paint(Paint::new(c, fill))
+        ));
+        bounds(&[pos.cast().unwrap()], rad as f32 + 1.0)
+    }
+}
+
+/// Renders every stroke of `document` as SVG shapes.
+pub fn svg_from_document(
+    document: &Document,
+    registry: &BrushRegistry,
+    region: mxcfb_rect,
+) -> String {
+    let mut canvas = SvgCanvas::new(region);
+    document.render(&mut canvas, registry);
+    canvas.to_svg()
+}
+
+pub fn write_svg(
+    path: &Path,
+    document: &Document,
+    registry: &BrushRegistry,
+    region: mxcfb_rect,
+) -> io::Result<()> {
+    let svg = svg_from_document(document, registry, region);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, svg)
+}
+
+impl FramebufferDraw for SvgCanvas {
+    fn draw_image(&mut self, _img: &RgbImage, _pos: cgmath::Point2<i32>) -> mxcfb_rect {
+        mxcfb_rect::invalid()
+    }
+
+    fn draw_line(
+        &mut self,
+        start: cgmath::Point2<i32>,
+        end: cgmath::Point2<i32>,
+        width: u32,
+        v: color,
+    ) -> mxcfb_rect {
+        self.elements.push(format!(
+            "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" {} stroke-linecap=\"round\"/>",
+            start.x,
+            start.y,
+            end.x,
+            end.y,
+            paint(Paint::Stroke(v, width.max(1) as f32))
+        ));
+        bounds(
+            &[start.cast().unwrap(), end.cast().unwrap()],
+            width as f32 / 2.0,
+        )
+    }
+
+    fn draw_polygon(&mut self, points: &[cgmath::Point2<i32>], fill: bool, c: color) -> mxcfb_rect {
+        let list = points
+            .iter()
+            .map(|p| format!("{},{}", p.x, p.y))
+            .collect::<Vec<String>>()
+            .join(" ");
+        self.elements.push(format!(
+            "<polygon points=\"{}\" {}/>",
+            list,
+            paint(Paint::new(c, fill))
+        ));
+        let points: Vec<cgmath::Point2<f32>> = points.iter().map(|p| p.cast().unwrap()).collect();
+        bounds(&points, 1.0)
+    }
+
+    fn draw_circle(&mut self, pos: cgmath::Point2<i32>, rad: u32, c: color) -> mxcfb_rect {
+        self.circle(pos, rad, c, false)
+    }
+
+    fn fill_circle(&mut self, pos: cgmath::Point2<i32>, rad: u32, c: color) -> mxcfb_rect {
+        self.circle(pos, rad, c, true)
+    }
+
+    fn draw_bezier(
+        &mut self,
+        startpt: cgmath::Point2<f32>,
+        ctrlpt: cgmath::Point2<f32>,
+        endpt: cgmath::Point2<f32>,
+        width: f32,
+        _samples: i32,
+        v: color,
+    ) -> mxcfb_rect {
+        self.elements.push(format!(
+            "<path d=\"M {} Q {} {}\" {} stroke-linecap=\"round\"/>",
+            point(startpt),
+            point(ctrlpt),
+            point(endpt),
+            paint(Paint::Stroke(v, width))
+        ));
+        bounds(&[startpt, ctrlpt, endpt], width / 2.0)
+    }
+
+    fn draw_dynamic_bezier(
+        &mut self,
+        startpt: (cgmath::Point2<f32>, f32),
+        ctrlpt: (cgmath::Point2<f32>, f32),
+        endpt: (cgmath::Point2<f32>, f32),
+        _samples: i32,
+        v: color,
+    ) -> mxcfb_rect {
+        let max_width = startpt.1.max(ctrlpt.1).max(endpt.1);
+        let rect = bounds(&[startpt.0, ctrlpt.0, endpt.0], max_width / 2.0);
+        match outline(startpt, ctrlpt, endpt) {
+            Some(path) => {
+                self.elements
+                    .push(format!("<path d=\"{}\" {}/>", path, paint(Paint::Fill(v))))
+            }
+            // All three points coincide, which leaves a dot
+            None => self.elements.push(format!(
+                "<circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"{:.2}\" {}/>",
+                startpt.0.x,
+                startpt.0.y,
+                max_width / 2.0,
+                paint(Paint::Fill(v))
+            )),
+        }
+        rect
+    }
+
+    fn draw_text(
+        &mut self,
+        _pos: cgmath::Point2<f32>,
+        _text: &str,
+        _size: f32,
+        _col: color,
+        _dryrun: bool,
+    ) -> mxcfb_rect {
+        mxcfb_rect::invalid()
+    }
+
+    fn draw_rect(
+        &mut self,
+        pos: cgmath::Point2<i32>,
+        size: cgmath::Vector2<u32>,
+        border_px: u32,
+        c: color,
+    ) {
+        self.elements.push(format!(
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" {}/>",
+            pos.x,
+            pos.y,
+            size.x,
+            size.y,
+            paint(Paint::Stroke(c, border_px as f32))
+        ));
+    }
+
+    fn fill_rect(&mut self, pos: cgmath::Point2<i32>, size: cgmath::Vector2<u32>, c: color) {
+        self.elements.push(format!(
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" {}/>",
+            pos.x,
+            pos.y,
+            size.x,
+            size.y,
+            paint(Paint::Fill(c))
+        ));
+    }
+
+    fn clear(&mut self) {
+        self.elements.clear();
+    }
+}
+
+/// SVG path outlining a variable width bezier, with round caps at both ends.
+fn outline(
+    startpt: (cgmath::Point2<f32>, f32),
+    ctrlpt: (cgmath::Point2<f32>, f32),
+    endpt: (cgmath::Point2<f32>, f32),
+) -> Option<String> {
+    let (left, right) = bezier_outline(startpt, ctrlpt, endpt)?;
+    let mut path = format!("M {}", point(left[0]));
+    for p in left.iter().skip(1) {
+        path.push_str(&format!(" L {}", point(*p)));
+    }
+    path.push_str(&format!(
+        " A {0:.2} {0:.2} 0 0 0 {1}",
+        endpt.1 / 2.0,
+        point(right[right.len() - 1])
+    ));
+    for p in right.iter().rev().skip(1) {
+        path.push_str(&format!(" L {}", point(*p)));
+    }
+    path.push_str(&format!(
+        " A {0:.2} {0:.2} 0 0 0 {1} Z",
+        startpt.1 / 2.0,
+        point(left[0])
+    ));
+    Some(path)
+}
+
+fn point(p: cgmath::Point2<f32>) -> String {
+    format!("{:.2} {:.2}", p.x, p.y)
+}
+
+fn hex(c: color) -> String {
+    let [r, g, b] = c.to_rgb8();
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// Attributes painting a shape with `paint`.
+fn paint(paint: Paint) -> String {
+    match paint {
+        Paint::Fill(c) => format!("fill=\"{}\"", hex(c)),
+        Paint::Stroke(c, width) => format!(
+            "fill=\"none\" stroke=\"{}\" stroke-width=\"{}\"",
+            hex(c),
+            // Two decimals like the coordinates, whole widths without any
+            (width * 100.0).round() / 100.0
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brushes::{PenSample, StrokeStyle};
+    use crate::pressure::{PressureCurve, VelocityResponse};
+    use std::time::{Duration, Instant};
+
+    // Offset from the screen's corner, like the app's canvas
+    const REGION: mxcfb_rect = mxcfb_rect {
+        top: 100,
+        left: 50,
+        width: 200,
+        height: 150,
+    };
+
+    /// Value of the numeric attribute `name` of an SVG element.
+    fn attr(element: &str, name: &str) -> f32 {
+        let start = element.find(&format!(" {}=\"", name)).unwrap() + name.len() + 3;
+        let end = start + element[start..].find('"').unwrap();
+        element[start..end].parse().unwrap()
+    }
+
+    /// A white chrome stroke running right at height `y`, with its highlights
+    /// above it.
+    fn document(y: f32) -> Document {
+        let style = StrokeStyle {
+            color: color::WHITE,
+            size: 3,
+            pressure_curve: PressureCurve::Linear,
+            velocity: VelocityResponse::NONE,
+            clip: REGION,
+        };
+        let start = Instant::now();
+        let mut document = Document::new();
+        for i in 0..8 {
+            let sample = PenSample {
+                position: cgmath::Point2::new(60.0 + i as f32 * 4.0, y),
+                pressure: 2048,
+                tilt: cgmath::vec2(0, 0),
+                time: start + Duration::from_millis(i * 10),
+            };
+            if i == 0 {
+                document.begin_stroke("chrome", style, sample);
+            } else {
+                document.add_point(sample);
+            }
+        }
+        document.end_stroke();
+        document
+    }
+
+    #[test]
+    fn sizes_the_view_to_the_region() {
+        let svg = svg_from_document(&Document::new(), &BrushRegistry::default(), REGION);
+        assert!(svg.contains("width=\"200\" height=\"150\" viewBox=\"0 0 200 150\""));
+        assert!(svg.contains("<g transform=\"translate(-50 -100)\">"));
+    }
+
+    #[test]
+    fn draws_strokes_in_their_color_and_width() {
+        let svg = svg_from_document(&document(150.0), &BrushRegistry::default(), REGION);
+        let lines: Vec<&str> = svg.lines().filter(|l| l.starts_with("<line")).collect();
+        // The pen's path in the stroke's color and size
+        let path: Vec<&&str> = lines
+            .iter()
+            .filter(|l| l.contains("stroke=\"#ffffff\" stroke-width=\"3\""))
+            .collect();
+        assert_eq!(path.len(), 7);
+        assert_eq!(attr(path[0], "x1"), 60.0);
+        assert_eq!(attr(path[0], "x2"), 64.0);
+        // And highlights in the opposite color
+        assert!(lines
+            .iter()
+            .any(|l| l.contains("stroke=\"#000000\" stroke-width=\"1\"")));
+    }
+
+    #[test]
+    fn keeps_shapes_inside_the_region() {
+        // Along the top edge, so the highlights would be off the canvas
+        let svg = svg_from_document(
+            &document(REGION.top as f32),
+            &BrushRegistry::default(),
+            REGION,
+        );
+        assert!(!svg.contains("stroke=\"#000000\""));
+        let mut lines = 0;
+        for line in svg.lines().filter(|l| l.starts_with("<line")) {
+            for (x, y) in [("x1", "y1"), ("x2", "y2")] {
+                let point = cgmath::Point2::new(attr(line, x) as u32, attr(line, y) as u32);
+                assert!(
+                    REGION.contains_point(&point),
+                    "outside the region: {}",
+                    line
+                );
+            }
+            lines += 1;
+        }
+        assert!(lines > 7);
+    }
+}
@@ -0,0 +1,174 @@
+//! Golden image tests for the brushes and canvas operations.
+//!
+//! Each test draws a scripted stroke through a session into a headless
+//! framebuffer and compares the canvas with a reference PNG in `tests/golden`.
+//! After an intended change to how something draws, regenerate the references
+//! with `HARMONIZERS_BLESS=1 cargo test golden` and look over the new images
+//! before committing them.
+
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::FramebufferIO;
+use libremarkable::image::{self, GrayImage};
+use libremarkable::input::{InputEvent, WacomEvent, WacomPen};
+
+use std::f32::consts::PI;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::export;
+use crate::headless::HeadlessFramebuffer;
+use crate::recording::{Event, Recorded};
+use crate::{Session, CANVAS_REGION};
+
+/// Gray levels a pixel may be off by before it counts as different.
+const LEVEL_TOLERANCE: i16 = 24;
+/// Share of the canvas allowed to differ before the images don't match.
+const MAX_DIFFERING: f32 = 0.001;
+/// Time between the samples of the scripted stroke, about what the digitizer
+/// reports at.
+const SAMPLE_INTERVAL: Duration = Duration::from_micros(7_500);
+
+fn pen(event: WacomEvent) -> Event {
+    Event::Input(InputEvent::WacomEvent { event })
+}
+
+/// A wave across the canvas, pressing harder towards the middle.
+fn scripted_stroke() -> Vec<Recorded> {
+    let mut events = vec![Recorded {
+        time: Duration::from_secs(0),
+        event: pen(WacomEvent::InstrumentChange {
+            pen: WacomPen::ToolPen,
+            state: true,
+        }),
+    }];
+    let samples = 120;
+    let mut time = SAMPLE_INTERVAL;
+    for i in 0..=samples {
+        let t = i as f32 / samples as f32;
+        let x = 200.0 + t * 1000.0;
+        let y = CANVAS_REGION.top as f32 + 540.0 + (t * 3.0 * PI).sin() * 200.0;
+        events.push(Recorded {
+            time,
+            event: pen(WacomEvent::Draw {
+                position: cgmath::Point2::new(x, y),
+                pressure: (600.0 + (t * PI).sin() * 2400.0) as u16,
+                tilt: cgmath::vec2(0, 0),
+            }),
+        });
+        time += SAMPLE_INTERVAL;
+    }
+    for (pen_kind, state) in [(WacomPen::Touch, false), (WacomPen::ToolPen, false)] {
+        events.push(Recorded {
+            time,
+            event: pen(WacomEvent::InstrumentChange {
+                pen: pen_kind,
+                state,
+            }),
+        });
+    }
+    // Leave time for animated brushes to settle after the pen lifts
+    events.push(Recorded {
+        time: time + Duration::from_secs(2),
+        event: Event::Input(InputEvent::Unknown {}),
+    });
+    events
+}
+
+/// Draws the scripted stroke with `brush` using the default pen settings.
+fn draw_stroke(brush: &str) -> (Session, HeadlessFramebuffer) {
+    let mut framebuffer = HeadlessFramebuffer::default();
+    let mut session = Session::new(CANVAS_REGION);
+    assert!(session.select_brush(brush));
+    session.reset(&framebuffer).unwrap();
+    session.replay(&mut framebuffer, &scripted_stroke());
+    (session, framebuffer)
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name)
+        .with_extension("png")
+}
+
+/// Compares the canvas with its reference image, or replaces the reference
+/// when blessing.
+fn check(name: &str, framebuffer: &HeadlessFramebuffer) -> Result<(), String> {
+    let pixels = framebuffer.dump_region(CANVAS_REGION)?;
+    let path = golden_path(name);
+    let write = |path: &Path| {
+        export::write_png(path, CANVAS_REGION.width, CANVAS_REGION.height, &pixels)
+            .map_err(|err| format!("{}: {}", path.display(), err))
+    };
+    if std::env::var_os("HARMONIZERS_BLESS").is_some() {
+        return write(&path);
+    }
+
+    let actual =
+        export::grayscale_from_canvas(CANVAS_REGION.width, CANVAS_REGION.height, &pixels).unwrap();
+    let expected = match image::open(&path) {
+        Ok(expected) => expected.to_luma8(),
+        Err(err) => {
+            return Err(format!(
+                "{}: {}, bless the tests to create it",
+                path.display(),
+                err
+            ))
+        }
+    };
+    let differing = count_differing(&expected, &actual);
+    if differing as f32 > MAX_DIFFERING * (actual.width() * actual.height()) as f32 {
+        // Keep what was drawn around to look at next to the reference
+        let actual_path = std::env::temp_dir().join(format!("harmonizers-{}.png", name));
+        write(&actual_path)?;
+        return Err(format!(
+            "{}: {} pixels differ, drew {}",
+            name,
+            differing,
+            actual_path.display()
+        ));
+    }
+    Ok(())
+}
+
+fn count_differing(expected: &GrayImage, actual: &GrayImage) -> usize {
+    if expected.dimensions() != actual.dimensions() {
+        return (actual.width() * actual.height()) as usize;
+    }
+    expected
+        .pixels()
+        .zip(actual.pixels())
+        .filter(|(e, a)| (e.0[0] as i16 - a.0[0] as i16).abs() > LEVEL_TOLERANCE)
+        .count()
+}
+
+#[test]
+fn brushes_match_goldens() {
+    let names: Vec<&str> = Session::new(CANVAS_REGION).registry().names().collect();
+    let failures: Vec<String> = names
+        .into_iter()
+        .filter_map(|name| check(&format!("brush-{}", name), &draw_stroke(name).1).err())
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn invert_matches_golden() {
+    let (mut session, mut framebuffer) = draw_stroke("simple");
+    session.invert(&mut framebuffer).unwrap();
+    check("invert", &framebuffer).unwrap();
+}
+
+#[test]
+fn blur_matches_golden() {
+    let (mut session, mut framebuffer) = draw_stroke("simple");
+    session.blur(&mut framebuffer).unwrap();
+    check("blur", &framebuffer).unwrap();
+}
+
+#[test]
+fn zoom_out_matches_golden() {
+    let (mut session, mut framebuffer) = draw_stroke("simple");
+    session.zoom_out(&mut framebuffer).unwrap();
+    check("zoom-out", &framebuffer).unwrap();
+}
@@ -0,0 +1,560 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::PartialRefreshMode;
+use libremarkable::framebuffer::{FramebufferDraw, FramebufferIO, FramebufferRefresh};
+use libremarkable::image::RgbImage;
+
+use std::mem;
+use std::sync::Mutex;
+
+/// Anything the app can draw on, be it the device framebuffer or a headless one.
+pub trait Surface: FramebufferDraw + FramebufferIO + FramebufferRefresh {}
+
+impl<T: FramebufferDraw + FramebufferIO + FramebufferRefresh> Surface for T {}
+
+// Pixels are kept in the native RGB565 of the reMarkable 2 framebuffer, so
+// dumped regions look exactly like the ones dumped on the device
+const BYTES_PER_PIXEL: usize = 2;
+
+/// A refresh of the display asked for by the app.
+#[derive(Copy, Clone, Debug)]
+pub struct Refresh {
+    pub region: mxcfb_rect,
+    pub waveform_mode: waveform_mode,
+    pub dither_mode: dither_mode,
+    pub quant_bit: i32,
+    /// Whether the whole screen was refreshed
+    pub full: bool,
+}
+
+/// Framebuffer kept in memory, for running the drawing code without a device.
+///
+/// It mimics the drawing primitives of libremarkable with its own
+/// rasterisation: Bresenham lines stamped with filled circles for width,
+/// midpoint circles, even-odd polygon fills and beziers sampled into line
+/// segments. Shapes land where they would on the device, but their edges
+/// aren't guaranteed to match it pixel for pixel, so goldens check this
+/// implementation rather than what the device shows. Refreshes only get
+/// recorded. Text isn't drawn at all: it needs the font built into
+/// libremarkable, so `draw_text` only reports where the text would have gone.
+pub struct HeadlessFramebuffer {
+    width: u32,
+    height: u32,
+    frame: Vec<u8>,
+    refreshes: Mutex<Vec<Refresh>>,
+}
+
+impl Default for HeadlessFramebuffer {
+    fn default() -> Self {
+        HeadlessFramebuffer::new(DISPLAYWIDTH as u32, DISPLAYHEIGHT as u32)
+    }
+}
+
+impl HeadlessFramebuffer {
+    /// A blank white framebuffer of the given size.
+    pub fn new(width: u32, height: u32) -> Self {
+        HeadlessFramebuffer {
+            width,
+            height,
+            frame: color::WHITE
+                .as_native()
+                .repeat(width as usize * height as usize),
+            refreshes: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Refreshes asked for since the last call, oldest first.
+    pub fn take_refreshes(&self) -> Vec<Refresh> {
+        mem::take(&mut *self.refreshes.lock().unwrap())
+    }
+
+    fn offset(&self, x: u32, y: u32) -> usize {
+        (y as usize * self.width as usize + x as usize) * BYTES_PER_PIXEL
+    }
+
+    fn contains(&self, rect: &mxcfb_rect) -> bool {
+        rect.left + rect.width <= self.width && rect.top + rect.height <= self.height
+    }
+
+    /// The part of the screen within the given bounds, right and bottom excluded.
+    fn clip(&self, left: i32, top: i32, right: i32, bottom: i32) -> mxcfb_rect {
+        let left = left.max(0);
+        let top = top.max(0);
+        let right = right.min(self.width as i32);
+        let bottom = bottom.min(self.height as i32);
+        if right <= left || bottom <= top {
+            return mxcfb_rect::invalid();
+        }
+        mxcfb_rect {
+            top: top as u32,
+            left: left as u32,
+            width: (right - left) as u32,
+            height: (bottom - top) as u32,
+        }
+    }
+
+    fn fill_span(&mut self, y: i32, x0: i32, x1: i32, c: color) {
+        if y < 0 || y >= self.height as i32 {
+            return;
+        }
+        let native = c.as_native();
+        let x0 = x0.max(0);
+        let x1 = x1.min(self.width as i32 - 1);
+        for x in x0..=x1 {
+            let offset = self.offset(x as u32, y as u32);
+            self.frame[offset..offset + BYTES_PER_PIXEL].copy_from_slice(&native);
+        }
+    }
+
+    fn record(&self, refresh: Refresh) -> u32 {
+        let mut refreshes = self.refreshes.lock().unwrap();
+        refreshes.push(refresh);
+        refreshes.len() as u32
+    }
+}
+
+impl FramebufferDraw for HeadlessFramebuffer {
+    fn draw_image(&mut self, img: &RgbImage, pos: cgmath::Point2<i32>) -> mxcfb_rect {
+        for (x, y, pixel) in img.enumerate_pixels() {
+            let [r, g, b] = pixel.0;
+            self.write_pixel(
+                cgmath::Point2::new(pos.x + x as i32, pos.y + y as i32),
+                color::RGB(r, g, b),
+            );
+        }
+        self.clip(
+            pos.x,
+            pos.y,
+            pos.x + img.width() as i32,
+            pos.y + img.height() as i32,
+        )
+    }
+
+    fn draw_line(
+        &mut self,
+        start: cgmath::Point2<i32>,
+        end: cgmath::Point2<i32>,
+        width: u32,
+        v: color,
+    ) -> mxcfb_rect {
+        let radius = width as i32 / 2;
+        for point in line_points(start, end) {
+            if width <= 1 {
+                self.write_pixel(point, v);
+            } else {
+                self.fill_circle(point, radius as u32, v);
+            }
+        }
+        self.clip(
+            start.x.min(end.x) - radius,
+            start.y.min(end.y) - radius,
+            start.x.max(end.x) + radius + 1,
+            start.y.max(end.y) + radius + 1,
+        )
+    }
+
+    fn draw_polygon(&mut self, points: &[cgmath::Point2<i32>], fill: bool, c: color) -> mxcfb_rect {
+        if points.is_empty() {
+            return mxcfb_rect::invalid();
+        }
+        // The outline is drawn for filled polygons too, so thin ones don't vanish
+        for (i, start) in points.iter().enumerate() {
+            let end = points[(i + 1) % points.len()];
+            self.draw_line(*start, end, 1, c);
+        }
+        let top = points.iter().map(|p| p.y).min().unwrap();
+        let bottom = points.iter().map(|p| p.y).max().unwrap();
+        if fill {
+            for y in top..=bottom {
+                // Even-odd scanline fill sampled through the middle of the row
+                let scan = y as f32 + 0.5;
+                let mut crossings = Vec::new();
+                for (i, a) in points.iter().enumerate() {
+                    let b = points[(i + 1) % points.len()];
+                    let (ay, by) = (a.y as f32, b.y as f32);
+                    if (ay <= scan) != (by <= scan) {
+                        let t = (scan - ay) / (by - ay);
+                        crossings.push(a.x as f32 + t * (b.x - a.x) as f32);
+                    }
+                }
+                crossings.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                for pair in crossings.chunks(2) {
+                    if let [from, to] = pair {
+                        let x0 = (from - 0.5).ceil() as i32;
+                        let x1 = (to - 0.5).floor() as i32;
+                        self.fill_span(y, x0, x1, c);
+                    }
+                }
+            }
+        }
+        let left = points.iter().map(|p| p.x).min().unwrap();
+        let right = points.iter().map(|p| p.x).max().unwrap();
+        self.clip(left, top, right + 1, bottom + 1)
+    }
+
+    fn draw_circle(&mut self, pos: cgmath::Point2<i32>, rad: u32, c: color) -> mxcfb_rect {
+        // Midpoint circle, mirrored into all eight octants
+        let (mut x, mut y) = (rad as i32, 0);
+        let mut err = 1 - x;
+        while x >= y {
+            for (dx, dy) in [
+                (x, y),
+                (y, x),
+                (-y, x),
+                (-x, y),
+                (-x, -y),
+                (-y, -x),
+                (y, -x),
+                (x, -y),
+            ] {
+                self.write_pixel(cgmath::Point2::new(pos.x + dx, pos.y + dy), c);
+            }
+            y += 1;
+            if err < 0 {
+                err += 2 * y + 1;
+            } else {
+                x -= 1;
+                err += 2 * (y - x) + 1;
+            }
+        }
+        let rad = rad as i32;
+        self.clip(pos.x - rad, pos.y - rad, pos.x + rad + 1, pos.y + rad + 1)
+    }
+
+    fn fill_circle(&mut self, pos: cgmath::Point2<i32>, rad: u32, c: color) -> mxcfb_rect {
+        let rad = rad as i32;
+        for dy in -rad..=rad {
+            let dx = ((rad * rad - dy * dy) as f32).sqrt() as i32;
+            self.fill_span(pos.y + dy, pos.x - dx, pos.x + dx, c);
+        }
+        self.clip(pos.x - rad, pos.y - rad, pos.x + rad + 1, pos.y + rad + 1)
+    }
+
+    fn draw_bezier(
+        &mut self,
+        startpt: cgmath::Point2<f32>,
+        ctrlpt: cgmath::Point2<f32>,
+        endpt: cgmath::Point2<f32>,
+        width: f32,
+        samples: i32,
+        v: color,
+    ) -> mxcfb_rect {
+        self.draw_dynamic_bezier(
+            (startpt, width),
+            (ctrlpt, width),
+            (endpt, width),
+            samples,
+            v,
+        )
+    }
+
+    fn draw_dynamic_bezier(
+        &mut self,
+        startpt: (cgmath::Point2<f32>, f32),
+        ctrlpt: (cgmath::Point2<f32>, f32),
+        endpt: (cgmath::Point2<f32>, f32),
+        samples: i32,
+        v: color,
+    ) -> mxcfb_rect {
+        let samples = samples.max(1);
+        let at = |t: f32| {
+            let (a, b, c) = ((1.0 - t) * (1.0 - t), 2.0 * (1.0 - t) * t, t * t);
+            let x = a * startpt.0.x + b * ctrlpt.0.x + c * endpt.0.x;
+            let y = a * startpt.0.y + b * ctrlpt.0.y + c * endpt.0.y;
+            let width = a * startpt.1 + b * ctrlpt.1 + c * endpt.1;
+            (
+                cgmath::Point2::new(x.round() as i32, y.round() as i32),
+                width,
+            )
+        };
+        let mut rect = mxcfb_rect::invalid();
+        let mut prev = at(0.0);
+        for i in 1..=samples {
+            let next = at(i as f32 / samples as f32);
+            let width = ((prev.1 + next.1) / 2.0).round().max(1.0) as u32;
+            rect = rect.merge_rect(&self.draw_line(prev.0, next.0, width, v));
+            prev = next;
+        }
+        rect
+    }
+
+    fn draw_text(
+        &mut self,
+        pos: cgmath::Point2<f32>,
+        text: &str,
+        size: f32,
+        _col: color,
+        _dryrun: bool,
+    ) -> mxcfb_rect {
+        // Rough guess at the extent of the text, which sits on its baseline
+        let width = (text.chars().count() as f32 * size * 0.6) as i32;
+        let (x, y) = (pos.x as i32, pos.y as i32);
+        self.clip(x, y - size as i32, x + width, y)
+    }
+
+    fn draw_rect(
+        &mut self,
+        pos: cgmath::Point2<i32>,
+        size: cgmath::Vector2<u32>,
+        border_px: u32,
+        c: color,
+    ) {
+        let border = border_px.min(size.x).min(size.y);
+        let (right, bottom) = (
+            pos.x + (size.x - border) as i32,
+            pos.y + (size.y - border) as i32,
+        );
+        self.fill_rect(pos, cgmath::vec2(size.x, border), c);
+        self.fill_rect(
+            cgmath::Point2::new(pos.x, bottom),
+            cgmath::vec2(size.x, border),
+            c,
+        );
+        self.fill_rect(pos, cgmath::vec2(border, size.y), c);
+        self.fill_rect(
+            cgmath::Point2::new(right, pos.y),
+            cgmath::vec2(border, size.y),
+            c,
+        );
+    }
+
+    fn fill_rect(&mut self, pos: cgmath::Point2<i32>, size: cgmath::Vector2<u32>, c: color) {
+        if size.x == 0 {
+            return;
+        }
+        for y in pos.y..pos.y + size.y as i32 {
+            self.fill_span(y, pos.x, pos.x + size.x as i32 - 1, c);
+        }
+    }
+
+    fn clear(&mut self) {
+        let width = self.width as i32;
+        for y in 0..self.height as i32 {
+            self.fill_span(y, 0, width - 1, color::WHITE);
+        }
+    }
+}
+
+impl FramebufferIO for HeadlessFramebuffer {
+    fn write_frame(&mut self, frame: &[u8]) {
+        let len = frame.len().min(self.frame.len());
+        self.frame[..len].copy_from_slice(&frame[..len]);
+    }
+
+    fn write_pixel(&mut self, pos: cgmath::Point2<i32>, col: color) {
+        self.fill_span(pos.y, pos.x, pos.x, col);
+    }
+
+    fn read_pixel(&self, pos: cgmath::Point2<u32>) -> color {
+        if pos.x >= self.width || pos.y >= self.height {
+            return color::WHITE;
+        }
+        let offset = self.offset(pos.x, pos.y);
+        color::from_native([self.frame[offset], self.frame[offset + 1]])
+    }
+
+    fn read_offset(&self, ofst: isize) -> u8 {
+        self.frame.get(ofst as usize).copied().unwrap_or(0)
+    }
+
+    fn dump_region(&self, rect: mxcfb_rect) -> Result<Vec<u8>, &'static str> {
+        if !self.contains(&rect) {
+            return Err("Region is outside of the framebuffer");
+        }
+        let mut pixels =
+            Vec::with_capacity(rect.width as usize * rect.height as usize * BYTES_PER_PIXEL);
+        for y in rect.top..rect.top + rect.height {
+            let start = self.offset(rect.left, y);
+            pixels.extend_from_slice(
+                &self.frame[start..start + rect.width as usize * BYTES_PER_PIXEL],
+            );
+        }
+        Ok(pixels)
+    }
+
+    fn restore_region(&mut self, rect: mxcfb_rect, data: &[u8]) -> Result<u32, &'static str> {
+        if !self.contains(&rect) {
+            return Err("Region is outside of the framebuffer");
+        }
+        let stride = rect.width as usize * BYTES_PER_PIXEL;
+        if data.len() != stride * rect.height as usize {
+            return Err("Data doesn't match the size of the region");
+        }
+        for (row, line) in data.chunks(stride).enumerate() {
+            let start = self.offset(rect.left, rect.top + row as u32);
+            self.frame[start..start + stride].copy_from_slice(line);
+        }
+        Ok(data.len() as u32)
+    }
+}
+
+impl FramebufferRefresh for HeadlessFramebuffer {
+    fn full_refresh(
+        &self,
+        waveform_mode: waveform_mode,
+        _temperature: display_temp,
+        dither_mode: dither_mode,
+        quant_bit: i32,
+        _wait_completion: bool,
+    ) -> u32 {
+        self.record(Refresh {
+            region: self.clip(0, 0, self.width as i32, self.height as i32),
+            waveform_mode,
+            dither_mode,
+            quant_bit,
+            full: true,
+        })
+    }
+
+    fn partial_refresh(
+        &self,
+        region: &mxcfb_rect,
+        mode: PartialRefreshMode,
+        waveform_mode: waveform_mode,
+        _temperature: display_temp,
+        dither_mode: dither_mode,
+        quant_bit: i32,
+        force_full_refresh: bool,
+    ) -> u32 {
+        if mode == PartialRefreshMode::DryRun {
+            return 0;
+        }
+        self.record(Refresh {
+            region: *region,
+            waveform_mode,
+            dither_mode,
+            quant_bit,
+            full: force_full_refresh,
+        })
+    }
+
+    fn wait_refresh_complete(&self, marker: u32) -> u32 {
+        // Nothing to wait for
+        marker
+    }
+}
+
+/// Pixels on the line from `start` to `end`, both included.
+fn line_points(start: cgmath::Point2<i32>, end: cgmath::Point2<i32>) -> Vec<cgmath::Point2<i32>> {
+    let (dx, dy) = ((end.x - start.x).abs(), -(end.y - start.y).abs());
+    let (sx, sy) = ((end.x - start.x).signum(), (end.y - start.y).signum());
+    let mut err = dx + dy;
+    let mut point = start;
+    let mut points = vec![point];
+    while point != end {
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            point.x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            point.y += sy;
+        }
+        points.push(point);
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: [u8; 2] = [0x00, 0x00];
+    const WHITE: [u8; 2] = [0xff, 0xff];
+
+    fn rect(left: u32, top: u32, width: u32, height: u32) -> mxcfb_rect {
+        mxcfb_rect {
+            top,
+            left,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn draws_into_memory() {
+        let mut fb = HeadlessFramebuffer::new(16, 8);
+        let drawn = fb.draw_line(
+            cgmath::Point2::new(2, 3),
+            cgmath::Point2::new(12, 3),
+            1,
+            color::BLACK,
+        );
+        assert_eq!(drawn, rect(2, 3, 11, 1));
+
+        let row = fb.dump_region(rect(0, 3, 16, 1)).unwrap();
+        for (x, pixel) in row.chunks(2).enumerate() {
+            let expected = if (2..=12).contains(&x) { BLACK } else { WHITE };
+            assert_eq!(pixel, expected, "pixel {} of the line", x);
+        }
+        assert_eq!(
+            fb.read_pixel(cgmath::Point2::new(7, 3)),
+            color::from_native(BLACK)
+        );
+        assert!(fb
+            .dump_region(rect(0, 2, 16, 1))
+            .unwrap()
+            .iter()
+            .all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn restores_dumped_regions() {
+        let mut fb = HeadlessFramebuffer::new(16, 8);
+        let region = rect(4, 1, 6, 7);
+        let blank = fb.dump_region(region).unwrap();
+        fb.fill_circle(cgmath::Point2::new(7, 4), 2, color::BLACK);
+        assert_ne!(fb.dump_region(region).unwrap(), blank);
+
+        fb.restore_region(region, &blank).unwrap();
+        assert!(fb
+            .dump_region(rect(0, 0, 16, 8))
+            .unwrap()
+            .iter()
+            .all(|b| *b == 0xff));
+        assert!(fb.dump_region(rect(12, 0, 8, 8)).is_err());
+        assert!(fb.restore_region(region, &blank[2..]).is_err());
+    }
+
+    #[test]
+    fn records_refreshes() {
+        let fb = HeadlessFramebuffer::new(16, 8);
+        let region = rect(1, 1, 4, 4);
+        for mode in [PartialRefreshMode::Async, PartialRefreshMode::DryRun] {
+            fb.partial_refresh(
+                &region,
+                mode,
+                waveform_mode::WAVEFORM_MODE_DU,
+                display_temp::TEMP_USE_REMARKABLE_DRAW,
+                dither_mode::EPDC_FLAG_EXP1,
+                DRAWING_QUANT_BIT,
+                false,
+            );
+        }
+        fb.full_refresh(
+            waveform_mode::WAVEFORM_MODE_GC16,
+            display_temp::TEMP_USE_MAX,
+            dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
+            0,
+            true,
+        );
+
+        let refreshes = fb.take_refreshes();
+        assert_eq!(refreshes.len(), 2);
+        assert_eq!(refreshes[0].region, region);
+        assert!(matches!(
+            refreshes[0].waveform_mode,
+            waveform_mode::WAVEFORM_MODE_DU
+        ));
+        assert!(matches!(
+            refreshes[0].dither_mode,
+            dither_mode::EPDC_FLAG_EXP1
+        ));
+        assert_eq!(refreshes[0].quant_bit, DRAWING_QUANT_BIT);
+        assert!(!refreshes[0].full);
+        assert_eq!(refreshes[1].region, rect(0, 0, 16, 8));
+        assert!(refreshes[1].full);
+        assert!(fb.take_refreshes().is_empty());
+    }
+}
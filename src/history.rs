@@ -0,0 +1,454 @@
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferIO;
+
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+
+use crate::document::{Document, Stroke, StrokePoint};
+
+/// Memory the undo history may hold before the oldest edits are forgotten.
+pub const DEFAULT_BUDGET: usize = 32 * 1024 * 1024;
+
+// Low levels keep compressing a full canvas snapshot quick enough to not be
+// noticed between strokes
+const COMPRESSION_LEVEL: i32 = 1;
+
+/// A rectangle of canvas pixels, zstd compressed.
+struct Pixels {
+    rect: mxcfb_rect,
+    data: Vec<u8>,
+}
+
+impl Pixels {
+    fn compress(rect: mxcfb_rect, raw: &[u8]) -> io::Result<Self> {
+        Ok(Pixels {
+            rect,
+            data: zstd::encode_all(raw, COMPRESSION_LEVEL)?,
+        })
+    }
+
+    fn decompress(&self) -> io::Result<Vec<u8>> {
+        zstd::decode_all(self.data.as_slice())
+    }
+}
+
+/// How an edit changed the document alongside the pixels.
+enum Change {
+    /// Only pixels changed, like blurring or inverting the canvas
+    Pixels,
+    /// Strokes were appended
+    Strokes(Vec<Stroke>),
+    /// The whole document was swapped out, like loading a saved canvas
+    Document {
+        before: Box<Document>,
+        after: Box<Document>,
+    },
+}
+
+struct Edit {
+    before: Pixels,
+    after: Pixels,
+    change: Change,
+}
+
+impl Edit {
+    fn size(&self) -> usize {
+        let points = |strokes: &[Stroke]| {
+            strokes
+                .iter()
+                .map(|stroke| stroke.points.len() * mem::size_of::<StrokePoint>())
+                .sum::<usize>()
+        };
+        let change = match self.change {
+            Change::Pixels => 0,
+            Change::Strokes(ref strokes) => points(strokes),
+            Change::Document {
+                ref before,
+                ref after,
+            } => points(&before.strokes) + points(&after.strokes),
+        };
+        self.before.data.len() + self.after.data.len() + change
+    }
+}
+
+/// Undo and redo stacks for everything that changes the canvas.
+///
+/// The history keeps a copy of the canvas as of the last recorded edit. Edits
+/// only store the pixels of the region they touched before and after, so a
+/// stroke costs a few kilobytes while a full canvas operation costs two
+/// compressed snapshots. Once the stored edits exceed the memory budget the
+/// oldest ones are dropped.
+pub struct History {
+    region: mxcfb_rect,
+    /// Raw pixels of the canvas region after the last recorded edit
+    canvas: Vec<u8>,
+    /// Region drawn to since the last recorded edit
+    dirty: mxcfb_rect,
+    /// Number of document strokes covered by recorded edits
+    strokes: usize,
+    undo: VecDeque<Edit>,
+    redo: Vec<Edit>,
+    used: usize,
+    budget: usize,
+}
+
+impl History {
+    pub fn new(region: mxcfb_rect, budget: usize) -> Self {
+        History {
+            region,
+            canvas: Vec::new(),
+            dirty: mxcfb_rect::invalid(),
+            strokes: 0,
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            used: 0,
+            budget,
+        }
+    }
+
+    /// Forgets every edit and takes the current canvas and document as the new
+    /// starting point.
+    pub fn reset(&mut self, fb: &dyn FramebufferIO, document: &Document) -> io::Result<()> {
+        self.canvas = fb.dump_region(self.region).map_err(to_io_error)?;
+        self.dirty = mxcfb_rect::invalid();
+        self.strokes = document.strokes.len();
+        self.undo.clear();
+        self.redo.clear();
+        self.used = 0;
+        Ok(())
+    }
+
+    /// Marks a region as drawn to by a stroke that hasn't been recorded yet.
+    pub fn mark_dirty(&mut self, rect: &mxcfb_rect) {
+        self.dirty = self.dirty.merge_rect(rect);
+    }
+
+    /// Records the strokes added to the document since the last edit, along with
+    /// the pixels drawn to since then. Without new strokes nothing is recorded
+    /// and the drawn region is kept for the next edit.
+    pub fn record_strokes(
+        &mut self,
+        fb: &dyn FramebufferIO,
+        document: &Document,
+    ) -> io::Result<()> {
+        let strokes = document.strokes[self.strokes.min(document.strokes.len())..].to_vec();
+        if strokes.is_empty() {
+            return Ok(());
+        }
+        self.strokes = document.strokes.len();
+        let rect = mem::replace(&mut self.dirty, mxcfb_rect::invalid());
+        self.record(fb, rect, Change::Strokes(strokes))
+    }
+
+    /// Records an operation that rewrote the pixels of the whole canvas.
+    pub fn record_canvas(&mut self, fb: &dyn FramebufferIO) -> io::Result<()> {
+        self.record(fb, self.region, Change::Pixels)
+    }
+
+    /// Records the whole canvas being replaced along with its document.
+    pub fn record_document(
+        &mut self,
+        fb: &dyn FramebufferIO,
+        before: Document,
+        after: Document,
+    ) -> io::Result<()> {
+        self.strokes = after.strokes.len();
+        let change = Change::Document {
+            before: Box::new(before),
+            after: Box::new(after),
+        };
+        self.record(fb, self.region, change)
+    }
+
+    /// Reverts the latest edit, returning the region to refresh.
+    pub fn undo(
+        &mut self,
+        fb: &mut dyn FramebufferIO,
+        document: &mut Document,
+    ) -> io::Result<Option<mxcfb_rect>> {
+        let edit = match self.undo.pop_back() {
+            Some(edit) => edit,
+            None => return Ok(None),
+        };
+        self.used -= edit.size();
+        self.apply(fb, &edit.before)?;
+        match edit.change {
+            Change::Pixels => {}
+            Change::Strokes(ref strokes) => {
+                let len = document.strokes.len().saturating_sub(strokes.len());
+                document.strokes.truncate(len);
+            }
+            Change::Document { ref before, .. } => *document = (**before).clone(),
+        }
+        self.strokes = document.strokes.len();
+        let rect = edit.before.rect;
+        self.redo.push(edit);
+        Ok(Some(rect))
+    }
+
+    /// Applies the latest undone edit again, returning the region to refresh.
+    pub fn redo(
+        &mut self,
+        fb: &mut dyn FramebufferIO,
+        document: &mut Document,
+    ) -> io::Result<Option<mxcfb_rect>> {
+        let edit = match self.redo.pop() {
+            Some(edit) => edit,
+            None => return Ok(None),
+        };
+        self.apply(fb, &edit.after)?;
+        match edit.change {
+            Change::Pixels => {}
+            Change::Strokes(ref strokes) => document.strokes.extend_from_slice(strokes),
+            Change::Document { ref after, .. } => *document = (**after).clone(),
+        }
+        self.strokes = document.strokes.len();
+        let rect = edit.after.rect;
+        self.push(edit);
+        Ok(Some(rect))
+    }
+
+    fn record(
+        &mut self,
+        fb: &dyn FramebufferIO,
+        rect: mxcfb_rect,
+        change: Change,
+    ) -> io::Result<()> {
+        if self.canvas.is_empty() {
+            return Err(io::Error::other("History hasn't been reset with a canvas"));
+        }
+        let rect = match intersect(&rect, &self.region) {
+            Some(rect) => rect,
+            None => return Ok(()),
+        };
+        let after = fb.dump_region(rect).map_err(to_io_error)?;
+        let before = self.crop(&rect);
+        let edit = Edit {
+            before: Pixels::compress(rect, &before)?,
+            after: Pixels::compress(rect, &after)?,
+            change,
+        };
+        self.paste(&rect, &after);
+        self.redo.clear();
+        self.push(edit);
+        Ok(())
+    }
+
+    fn push(&mut self, edit: Edit) {
+        self.used += edit.size();
+        self.undo.push_back(edit);
+        while self.used > self.budget && self.undo.len() > 1 {
+            if let Some(oldest) = self.undo.pop_front() {
+                self.used -= oldest.size();
+            }
+        }
+    }
+
+    fn apply(&mut self, fb: &mut dyn FramebufferIO, pixels: &Pixels) -> io::Result<()> {
+        let raw = pixels.decompress()?;
+        fb.restore_region(pixels.rect, &raw).map_err(to_io_error)?;
+        self.paste(&pixels.rect, &raw);
+        Ok(())
+    }
+
+    /// Bytes per pixel of the canvas, as of the last reset.
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.canvas.len() / (self.region.width * self.region.height) as usize
+    }
+
+    /// Byte range of the row of `rect` at `row` within the saved canvas.
+    fn row_range(&self, rect: &mxcfb_rect, row: u32) -> std::ops::Range<usize> {
+        let bpp = self.bytes_per_pixel();
+        let y = (rect.top - self.region.top + row) as usize;
+        let x = (rect.left - self.region.left) as usize;
+        let start = (y * self.region.width as usize + x) * bpp;
+        start..start + rect.width as usize * bpp
+    }
+
+    fn crop(&self, rect: &mxcfb_rect) -> Vec<u8> {
+        let mut pixels = Vec::new();
+        for row in 0..rect.height {
+            pixels.extend_from_slice(&self.canvas[self.row_range(rect, row)]);
+        }
+        pixels
+    }
+
+    fn paste(&mut self, rect: &mxcfb_rect, pixels: &[u8]) {
+        let stride = rect.width as usize * self.bytes_per_pixel();
+        for (row, line) in pixels.chunks(stride).enumerate() {
+            let range = self.row_range(rect, row as u32);
+            self.canvas[range].copy_from_slice(line);
+        }
+    }
+}
+
+fn intersect(a: &mxcfb_rect, b: &mxcfb_rect) -> Option<mxcfb_rect> {
+    let left = a.left.max(b.left);
+    let top = a.top.max(b.top);
+    let right = (a.left + a.width).min(b.left + b.width);
+    let bottom = (a.top + a.height).min(b.top + b.height);
+    if right <= left || bottom <= top {
+        return None;
+    }
+    Some(mxcfb_rect {
+        top,
+        left,
+        width: right - left,
+        height: bottom - top,
+    })
+}
+
+fn to_io_error(err: &'static str) -> io::Error {
+    io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brushes::{PenSample, StrokeStyle};
+    use crate::headless::HeadlessFramebuffer;
+    use crate::pressure::{PressureCurve, VelocityResponse};
+    use libremarkable::framebuffer::cgmath;
+    use libremarkable::framebuffer::FramebufferDraw;
+    use std::time::Instant;
+
+    // Away from the framebuffer's corner, so offsets into the saved canvas matter
+    const REGION: mxcfb_rect = mxcfb_rect {
+        top: 10,
+        left: 5,
+        width: 30,
+        height: 20,
+    };
+
+    fn setup(budget: usize) -> (History, HeadlessFramebuffer, Document) {
+        let fb = HeadlessFramebuffer::new(40, 40);
+        let document = Document::new();
+        let mut history = History::new(REGION, budget);
+        history.reset(&fb, &document).unwrap();
+        (history, fb, document)
+    }
+
+    /// Fills a square with its corner at `(x, y)` and adds a stroke for it.
+    fn stroke(
+        history: &mut History,
+        fb: &mut HeadlessFramebuffer,
+        document: &mut Document,
+        (x, y): (i32, i32),
+    ) {
+        let style = StrokeStyle {
+            color: color::BLACK,
+            size: 1,
+            pressure_curve: PressureCurve::Linear,
+            velocity: VelocityResponse::NONE,
+            clip: REGION,
+        };
+        let sample = PenSample {
+            position: cgmath::Point2::new(x as f32, y as f32),
+            pressure: 0,
+            tilt: cgmath::vec2(0, 0),
+            time: Instant::now(),
+        };
+        document.begin_stroke("simple", style, sample);
+        document.end_stroke();
+        scribble(history, fb, (x, y));
+    }
+
+    /// Fills a square with its corner at `(x, y)` without adding a stroke.
+    fn scribble(history: &mut History, fb: &mut HeadlessFramebuffer, (x, y): (i32, i32)) {
+        fb.fill_rect(cgmath::Point2::new(x, y), cgmath::vec2(6, 6), color::BLACK);
+        history.mark_dirty(&mxcfb_rect {
+            top: y as u32,
+            left: x as u32,
+            width: 6,
+            height: 6,
+        });
+    }
+
+    fn canvas(fb: &HeadlessFramebuffer) -> Vec<u8> {
+        fb.dump_region(REGION).unwrap()
+    }
+
+    #[test]
+    fn undo_and_redo_strokes() {
+        let (mut history, mut fb, mut document) = setup(DEFAULT_BUDGET);
+        let blank = canvas(&fb);
+        stroke(&mut history, &mut fb, &mut document, (8, 12));
+        history.record_strokes(&fb, &document).unwrap();
+        let first = canvas(&fb);
+        // Overlapping the first one, so its pixels have to come from the saved canvas
+        stroke(&mut history, &mut fb, &mut document, (11, 15));
+        history.record_strokes(&fb, &document).unwrap();
+        let second = canvas(&fb);
+
+        let rect = history.undo(&mut fb, &mut document).unwrap().unwrap();
+        assert_eq!(
+            (rect.left, rect.top, rect.width, rect.height),
+            (11, 15, 6, 6)
+        );
+        assert_eq!(canvas(&fb), first);
+        assert_eq!(document.strokes.len(), 1);
+        history.undo(&mut fb, &mut document).unwrap();
+        assert_eq!(canvas(&fb), blank);
+        assert!(document.is_empty());
+        assert!(history.undo(&mut fb, &mut document).unwrap().is_none());
+
+        history.redo(&mut fb, &mut document).unwrap();
+        history.redo(&mut fb, &mut document).unwrap();
+        assert_eq!(canvas(&fb), second);
+        assert_eq!(document.strokes.len(), 2);
+        assert!(history.redo(&mut fb, &mut document).unwrap().is_none());
+    }
+
+    #[test]
+    fn edits_are_clipped_to_the_canvas() {
+        let (mut history, mut fb, mut document) = setup(DEFAULT_BUDGET);
+        let blank = canvas(&fb);
+        // Hanging over the top left corner of the canvas
+        stroke(&mut history, &mut fb, &mut document, (2, 7));
+        history.record_strokes(&fb, &document).unwrap();
+
+        let rect = history.undo(&mut fb, &mut document).unwrap().unwrap();
+        assert_eq!(
+            (rect.left, rect.top, rect.width, rect.height),
+            (5, 10, 3, 3)
+        );
+        assert_eq!(canvas(&fb), blank);
+    }
+
+    #[test]
+    fn drawing_without_strokes_waits_for_the_next_edit() {
+        let (mut history, mut fb, mut document) = setup(DEFAULT_BUDGET);
+        stroke(&mut history, &mut fb, &mut document, (8, 12));
+        history.record_strokes(&fb, &document).unwrap();
+        let first = canvas(&fb);
+
+        // Like an animated brush still drawing after the pen lifted
+        scribble(&mut history, &mut fb, (25, 20));
+        history.record_strokes(&fb, &document).unwrap();
+        stroke(&mut history, &mut fb, &mut document, (20, 16));
+        history.record_strokes(&fb, &document).unwrap();
+
+        history.undo(&mut fb, &mut document).unwrap();
+        assert_eq!(canvas(&fb), first);
+    }
+
+    #[test]
+    fn oldest_edits_are_dropped_over_budget() {
+        let (mut history, mut fb, mut document) = setup(DEFAULT_BUDGET);
+        for _ in 0..3 {
+            history.record_canvas(&fb).unwrap();
+        }
+        for _ in 0..3 {
+            assert!(history.undo(&mut fb, &mut document).unwrap().is_some());
+        }
+
+        // The latest edit is kept even when it alone is over budget
+        let (mut history, mut fb, mut document) = setup(1);
+        for _ in 0..3 {
+            history.record_canvas(&fb).unwrap();
+        }
+        assert!(history.undo(&mut fb, &mut document).unwrap().is_some());
+        assert!(history.undo(&mut fb, &mut document).unwrap().is_none());
+    }
+}
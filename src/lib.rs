@@ -0,0 +1,23 @@
+//! The drawing engine behind harmonizers: procedural brushes, the canvas
+//! operations and undo history, kept apart from the reMarkable UI so it can be
+//! embedded in other apps or run without a device.
+//!
+//! A [`Session`] holds the state of one canvas and draws on any [`Surface`],
+//! the device framebuffer or a [`headless::HeadlessFramebuffer`].
+
+pub mod brushes;
+pub mod document;
+pub mod drawings;
+pub mod export;
+#[cfg(test)]
+mod golden;
+pub mod headless;
+pub mod history;
+pub mod pressure;
+pub mod recording;
+mod session;
+pub mod stabilizer;
+pub mod touch;
+
+pub use headless::Surface;
+pub use session::{DrawMode, Session, Settings, CANVAS_REGION};
@@ -0,0 +1,250 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Highest pressure the digitizer reports, plus one.
+const PRESSURE_RANGE: f32 = 4096.0;
+
+/// Response curve mapping raw digitizer pressure to stroke width.
+///
+/// Every curve maps `[0, 1]` onto `[0, 1]`; the result is scaled so that a
+/// linear curve reproduces the original `size * pressure / 2048 / 2` radius.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum PressureCurve {
+    #[default]
+    Linear,
+    /// Light strokes come out heavier
+    Soft,
+    /// It takes a firm press to get a wide stroke
+    Hard,
+    /// Cubic bezier from (0, 0) to (1, 1) with the given control points, like a
+    /// CSS `cubic-bezier()` easing
+    Bezier { x1: f32, y1: f32, x2: f32, y2: f32 },
+    /// Linear, but never thinner than the given fraction of the full width
+    Floor(f32),
+}
+
+impl PressureCurve {
+    pub fn toggle(self) -> Self {
+        match self {
+            PressureCurve::Linear => PressureCurve::Soft,
+            PressureCurve::Soft => PressureCurve::Hard,
+            PressureCurve::Hard => PressureCurve::Bezier {
+                x1: 0.1,
+                y1: 0.4,
+                x2: 0.6,
+                y2: 0.9,
+            },
+            PressureCurve::Bezier { .. } => PressureCurve::Floor(0.25),
+            PressureCurve::Floor(_) => PressureCurve::Linear,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PressureCurve::Linear => "linear",
+            PressureCurve::Soft => "soft",
+            PressureCurve::Hard => "hard",
+            PressureCurve::Bezier { .. } => "bezier",
+            PressureCurve::Floor(_) => "floor",
+        }
+    }
+
+    /// Width multiplier for a raw pressure reading, `1.0` at half the
+    /// digitizer's range on a linear curve.
+    pub fn factor(self, pressure: i32) -> f32 {
+        let x = (pressure as f32 / PRESSURE_RANGE).clamp(0.0, 1.0);
+        let y = match self {
+            PressureCurve::Linear => x,
+            PressureCurve::Soft => 1.0 - (1.0 - x) * (1.0 - x),
+            PressureCurve::Hard => x * x,
+            PressureCurve::Bezier { x1, y1, x2, y2 } => cubic_bezier(x1, y1, x2, y2, x),
+            PressureCurve::Floor(min) => x.max(min),
+        };
+        y * 2.0
+    }
+
+    /// Radius of a stroke drawn with `size` at the given raw pressure.
+    pub fn radius(self, size: u32, pressure: i32) -> f32 {
+        size as f32 * self.factor(pressure) / 2.0
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|err: String| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn save(self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, format!("{}\n", self))
+    }
+}
+
+impl fmt::Display for PressureCurve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PressureCurve::Bezier { x1, y1, x2, y2 } => {
+                write!(f, "{} {} {} {} {}", self.name(), x1, y1, x2, y2)
+            }
+            PressureCurve::Floor(min) => write!(f, "{} {}", self.name(), min),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+impl FromStr for PressureCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let params = words
+            .map(|w| w.parse::<f32>().map_err(|err| format!("{}: {}", w, err)))
+            .collect::<Result<Vec<f32>, String>>()?;
+        match (name, params.as_slice()) {
+            ("linear", []) => Ok(PressureCurve::Linear),
+            ("soft", []) => Ok(PressureCurve::Soft),
+            ("hard", []) => Ok(PressureCurve::Hard),
+            ("bezier", &[x1, y1, x2, y2]) => Ok(PressureCurve::Bezier { x1, y1, x2, y2 }),
+            ("floor", &[min]) => Ok(PressureCurve::Floor(min)),
+            _ => Err(format!("Unknown pressure curve: {}", s.trim())),
+        }
+    }
+}
+
+/// How much a stroke thins out as the pen speeds up.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct VelocityResponse {
+    /// Speed in pixels per millisecond at which the speed dependent part of the
+    /// width is halved
+    pub half_width_speed: f32,
+    /// Share of the width that depends on speed, `0.0` leaves the width to
+    /// pressure alone
+    pub blend: f32,
+}
+
+impl VelocityResponse {
+    pub const NONE: VelocityResponse = VelocityResponse {
+        half_width_speed: 1.0,
+        blend: 0.0,
+    };
+
+    /// Fast strokes come out at about half the width they would have at rest.
+    pub const THINNING: VelocityResponse = VelocityResponse {
+        half_width_speed: 2.0,
+        blend: 0.5,
+    };
+
+    /// Width multiplier at the given speed, `1.0` when the pen stands still.
+    pub fn factor(self, speed: f32) -> f32 {
+        let thinning = 1.0 / (1.0 + speed.max(0.0) / self.half_width_speed);
+        1.0 - self.blend + self.blend * thinning
+    }
+}
+
+/// Evaluates the easing curve at `x`, finding the bezier parameter by bisection
+/// since x(t) is monotonic for control points within `[0, 1]`.
+fn cubic_bezier(x1: f32, y1: f32, x2: f32, y2: f32, x: f32) -> f32 {
+    let at = |t: f32, p1: f32, p2: f32| {
+        let u = 1.0 - t;
+        3.0 * u * u * t * p1 + 3.0 * u * t * t * p2 + t * t * t
+    };
+    let (mut low, mut high) = (0.0f32, 1.0f32);
+    for _ in 0..32 {
+        let mid = (low + high) / 2.0;
+        if at(mid, x1, x2) < x {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    at((low + high) / 2.0, y1, y2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn linear_matches_original_mapping() {
+        for pressure in [0, 512, 1024, 2048, 3000, 4095] {
+            assert_close(
+                PressureCurve::Linear.radius(10, pressure),
+                (10.0 * pressure as f32 / 2048.) / 2.0,
+            );
+        }
+    }
+
+    #[test]
+    fn soft_and_hard_radii() {
+        assert_close(PressureCurve::Soft.radius(10, 1024), 4.375);
+        assert_close(PressureCurve::Soft.radius(10, 2048), 7.5);
+        assert_close(PressureCurve::Hard.radius(10, 1024), 0.625);
+        assert_close(PressureCurve::Hard.radius(10, 2048), 2.5);
+    }
+
+    #[test]
+    fn bezier_radii() {
+        let identity = PressureCurve::Bezier {
+            x1: 0.25,
+            y1: 0.25,
+            x2: 0.75,
+            y2: 0.75,
+        };
+        assert_close(identity.radius(10, 2048), 5.0);
+        assert_close(identity.radius(10, 1024), 2.5);
+
+        let eased = PressureCurve::Bezier {
+            x1: 0.1,
+            y1: 0.4,
+            x2: 0.6,
+            y2: 0.9,
+        };
+        assert_close(eased.radius(10, 0), 0.0);
+        assert_close(eased.radius(10, 4096), 10.0);
+        assert!(eased.radius(10, 1024) > PressureCurve::Linear.radius(10, 1024));
+    }
+
+    #[test]
+    fn floor_radii() {
+        let floor = PressureCurve::Floor(0.25);
+        assert_close(floor.radius(10, 0), 2.5);
+        assert_close(floor.radius(10, 512), 2.5);
+        assert_close(floor.radius(10, 2048), 5.0);
+    }
+
+    #[test]
+    fn velocity_thins_strokes() {
+        let response = VelocityResponse {
+            half_width_speed: 2.0,
+            blend: 0.5,
+        };
+        assert_close(response.factor(0.0), 1.0);
+        assert_close(response.factor(2.0), 0.75);
+        assert_close(VelocityResponse::NONE.factor(10.0), 1.0);
+    }
+
+    #[test]
+    fn round_trips_through_text() {
+        let mut curve = PressureCurve::Linear;
+        for _ in 0..5 {
+            assert_eq!(curve.to_string().parse::<PressureCurve>(), Ok(curve));
+            curve = curve.toggle();
+        }
+        assert!("bezier 1 2".parse::<PressureCurve>().is_err());
+    }
+}
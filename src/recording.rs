@@ -0,0 +1,582 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::input::{
+    Finger, GPIOEvent, InputEvent, MultitouchEvent, PhysicalButton, WacomEvent, WacomPen,
+};
+
+use std::fs;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::pressure::VelocityResponse;
+use crate::session::{DrawMode, Settings};
+
+/// First line of every recording, followed by the format version.
+const HEADER: &str = "harmonizers-input";
+const FORMAT_VERSION: u32 = 1;
+
+/// Something recorded while the app ran.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Input(InputEvent),
+    /// The settings changed, or got recorded for the first time
+    Settings(Settings),
+    Operation(Operation),
+}
+
+/// A change to the whole canvas made from the buttons around it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    Undo,
+    Redo,
+    Invert,
+    Blur,
+    ZoomOut,
+    /// The canvas got cleared to start over
+    Clear,
+    /// The drawing saved at the path got opened
+    Load(PathBuf),
+}
+
+/// An event along with when it happened, counted from the start of the
+/// recording.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recorded {
+    pub time: Duration,
+    pub event: Event,
+}
+
+/// Writes input events to a file as they are dispatched.
+///
+/// Recordings are plain text, one event per line starting with the
+/// microseconds since the recording started:
+///
+/// ```text
+/// harmonizers-input 1
+/// 120502 settings simple draw 2 None 2 0.5 1 0 off 4 linear
+/// 120511 pen draw 702.5 1204.25 1830 3 65532
+/// 120519 pen instrument touch 0
+/// 125003 touch press 7 650 1000
+/// 130022 button press left
+/// 130040 op clear
+/// 131507 op load /home/root/drawings/cat.hrmz
+/// ```
+///
+/// Settings list the brush, draw mode and size, touch mode, the speed
+/// response when drawing and erasing, the stabilizer and its strength and the
+/// pressure curve. Operations are what the buttons around the canvas did to
+/// it, since replays don't have those buttons. Events libremarkable couldn't
+/// make sense of aren't recorded.
+pub struct Recorder {
+    start: Instant,
+    out: BufWriter<fs::File>,
+    settings: Option<Settings>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut out = BufWriter::new(fs::File::create(path)?);
+        writeln!(out, "{} {}", HEADER, FORMAT_VERSION)?;
+        Ok(Recorder {
+            start: Instant::now(),
+            out,
+            settings: None,
+        })
+    }
+
+    /// Records `settings` unless they are what was recorded last.
+    pub fn record_settings(&mut self, settings: &Settings) -> io::Result<()> {
+        if self.settings.as_ref() == Some(settings) {
+            return Ok(());
+        }
+        self.settings = Some(settings.clone());
+        let fields = format_settings(settings);
+        writeln!(self.out, "{} {}", self.start.elapsed().as_micros(), fields)?;
+        self.out.flush()
+    }
+
+    pub fn record(&mut self, event: &InputEvent) -> io::Result<()> {
+        let fields = match format_event(event) {
+            Some(fields) => fields,
+            None => return Ok(()),
+        };
+        writeln!(self.out, "{} {}", self.start.elapsed().as_micros(), fields)?;
+        // A recording is most useful right after a crash, so don't sit on events
+        self.out.flush()
+    }
+
+    pub fn record_operation(&mut self, operation: &Operation) -> io::Result<()> {
+        let fields = format_operation(operation);
+        writeln!(self.out, "{} {}", self.start.elapsed().as_micros(), fields)?;
+        self.out.flush()
+    }
+}
+
+/// Reads back the events written by a `Recorder`, oldest first.
+pub fn load(path: &Path) -> io::Result<Vec<Recorded>> {
+    let mut lines = io::BufReader::new(fs::File::open(path)?).lines();
+    let header = lines.next().transpose()?.unwrap_or_default();
+    if header != format!("{} {}", HEADER, FORMAT_VERSION) {
+        return Err(invalid_data("Not an input recording".to_owned()));
+    }
+    let mut events = Vec::new();
+    for (number, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_line(&line) {
+            Some(recorded) => events.push(recorded),
+            // Line numbers count from one and the header took the first line
+            None => return Err(invalid_data(format!("Bad event on line {}", number + 2))),
+        }
+    }
+    Ok(events)
+}
+
+fn format_event(event: &InputEvent) -> Option<String> {
+    Some(match *event {
+        InputEvent::WacomEvent { event } => match event {
+            WacomEvent::Draw {
+                position,
+                pressure,
+                tilt,
+            } => format!(
+                "pen draw {} {} {} {} {}",
+                position.x, position.y, pressure, tilt.x, tilt.y
+            ),
+            WacomEvent::Hover {
+                position,
+                distance,
+                tilt,
+            } => format!(
+                "pen hover {} {} {} {} {}",
+                position.x, position.y, distance, tilt.x, tilt.y
+            ),
+            WacomEvent::InstrumentChange { pen, state } => {
+                format!("pen instrument {} {}", pen_name(pen), state as u8)
+            }
+            _ => return None,
+        },
+        InputEvent::MultitouchEvent { event } => {
+            let (action, finger) = match event {
+                MultitouchEvent::Press { finger } => ("press", finger),
+                MultitouchEvent::Move { finger } => ("move", finger),
+                MultitouchEvent::Release { finger } => ("release", finger),
+                _ => return None,
+            };
+            format!(
+                "touch {} {} {} {}",
+                action, finger.tracking_id, finger.pos.x, finger.pos.y
+            )
+        }
+        InputEvent::GPIO { event } => match event {
+            GPIOEvent::Press { button } => format!("button press {}", button_name(button)),
+            GPIOEvent::Unpress { button } => format!("button unpress {}", button_name(button)),
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+fn format_settings(settings: &Settings) -> String {
+    let (mode, size) = match settings.draw_mode {
+        DrawMode::Draw(size) => ("draw", size),
+        DrawMode::Erase(size) => ("erase", size),
+    };
+    format!(
+        "settings {} {} {} {} {} {} {} {} {} {} {}",
+        settings.brush,
+        mode,
+        size,
+        settings.touch_mode,
+        settings.draw_velocity.half_width_speed,
+        settings.draw_velocity.blend,
+        settings.erase_velocity.half_width_speed,
+        settings.erase_velocity.blend,
+        if settings.stabilizer_enabled {
+            "on"
+        } else {
+            "off"
+        },
+        settings.stabilizer_strength,
+        settings.pressure_curve
+    )
+}
+
+fn format_operation(operation: &Operation) -> String {
+    let name = match operation {
+        Operation::Undo => "undo",
+        Operation::Redo => "redo",
+        Operation::Invert => "invert",
+        Operation::Blur => "blur",
+        Operation::ZoomOut => "zoom-out",
+        Operation::Clear => "clear",
+        Operation::Load(path) => return format!("op load {}", path.display()),
+    };
+    format!("op {}", name)
+}
+
+/// Parses what follows `op` on a line, the path of a drawing can have spaces.
+fn parse_operation(fields: &str) -> Option<Operation> {
+    Some(match fields {
+        "undo" => Operation::Undo,
+        "redo" => Operation::Redo,
+        "invert" => Operation::Invert,
+        "blur" => Operation::Blur,
+        "zoom-out" => Operation::ZoomOut,
+        "clear" => Operation::Clear,
+        _ => Operation::Load(PathBuf::from(fields.strip_prefix("load ")?)),
+    })
+}
+
+fn parse_settings(fields: &[&str]) -> Option<Settings> {
+    match *fields {
+        [brush, mode, size, touch_mode, draw_speed, draw_blend, erase_speed, erase_blend, stabilizer, strength, ref curve @ ..] => {
+            Some(Settings {
+                brush: brush.to_owned(),
+                draw_mode: match mode {
+                    "draw" => DrawMode::Draw(size.parse().ok()?),
+                    "erase" => DrawMode::Erase(size.parse().ok()?),
+                    _ => return None,
+                },
+                touch_mode: touch_mode.parse().ok()?,
+                pressure_curve: curve.join(" ").parse().ok()?,
+                draw_velocity: VelocityResponse {
+                    half_width_speed: draw_speed.parse().ok()?,
+                    blend: draw_blend.parse().ok()?,
+                },
+                erase_velocity: VelocityResponse {
+                    half_width_speed: erase_speed.parse().ok()?,
+                    blend: erase_blend.parse().ok()?,
+                },
+                stabilizer_enabled: match stabilizer {
+                    "on" => true,
+                    "off" => false,
+                    _ => return None,
+                },
+                stabilizer_strength: strength.parse().ok()?,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn parse_line(line: &str) -> Option<Recorded> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let time = Duration::from_micros(fields.first()?.parse().ok()?);
+    if fields.get(1) == Some(&"settings") {
+        let settings = parse_settings(&fields[2..])?;
+        return Some(Recorded {
+            time,
+            event: Event::Settings(settings),
+        });
+    }
+    if fields.get(1) == Some(&"op") {
+        let operation = parse_operation(line.splitn(3, ' ').nth(2)?)?;
+        return Some(Recorded {
+            time,
+            event: Event::Operation(operation),
+        });
+    }
+    let event = match fields[1..] {
+        ["pen", "draw", x, y, pressure, tilt_x, tilt_y] => InputEvent::WacomEvent {
+            event: WacomEvent::Draw {
+                position: cgmath::Point2::new(x.parse().ok()?, y.parse().ok()?),
+                pressure: pressure.parse().ok()?,
+                tilt: cgmath::vec2(tilt_x.parse().ok()?, tilt_y.parse().ok()?),
+            },
+        },
+        ["pen", "hover", x, y, distance, tilt_x, tilt_y] => InputEvent::WacomEvent {
+            event: WacomEvent::Hover {
+                position: cgmath::Point2::new(x.parse().ok()?, y.parse().ok()?),
+                distance: distance.parse().ok()?,
+                tilt: cgmath::vec2(tilt_x.parse().ok()?, tilt_y.parse().ok()?),
+            },
+        },
+        ["pen", "instrument", pen, state] => InputEvent::WacomEvent {
+            event: WacomEvent::InstrumentChange {
+                pen: parse_pen(pen)?,
+                state: state.parse::<u8>().ok()? != 0,
+            },
+        },
+        ["touch", action, id, x, y] => {
+            let finger = Finger {
+                tracking_id: id.parse().ok()?,
+                pos: cgmath::Point2::new(x.parse().ok()?, y.parse().ok()?),
+                pos_updated: true,
+                last_pressed: action != "press",
+                pressed: action != "release",
+            };
+            InputEvent::MultitouchEvent {
+                event: match action {
+                    "press" => MultitouchEvent::Press { finger },
+                    "move" => MultitouchEvent::Move { finger },
+                    "release" => MultitouchEvent::Release { finger },
+                    _ => return None,
+                },
+            }
+        }
+        ["button", action, button] => {
+            let button = parse_button(button)?;
+            InputEvent::GPIO {
+                event: match action {
+                    "press" => GPIOEvent::Press { button },
+                    "unpress" => GPIOEvent::Unpress { button },
+                    _ => return None,
+                },
+            }
+        }
+        _ => return None,
+    };
+    Some(Recorded {
+        time,
+        event: Event::Input(event),
+    })
+}
+
+fn pen_name(pen: WacomPen) -> &'static str {
+    match pen {
+        WacomPen::ToolPen => "pen",
+        WacomPen::ToolRubber => "rubber",
+        WacomPen::Touch => "touch",
+        WacomPen::Stylus => "stylus",
+        WacomPen::Stylus2 => "stylus2",
+    }
+}
+
+fn parse_pen(name: &str) -> Option<WacomPen> {
+    Some(match name {
+        "pen" => WacomPen::ToolPen,
+        "rubber" => WacomPen::ToolRubber,
+        "touch" => WacomPen::Touch,
+        "stylus" => WacomPen::Stylus,
+        "stylus2" => WacomPen::Stylus2,
+        _ => return None,
+    })
+}
+
+fn button_name(button: PhysicalButton) -> &'static str {
+    match button {
+        PhysicalButton::LEFT => "left",
+        PhysicalButton::MIDDLE => "middle",
+        PhysicalButton::RIGHT => "right",
+        PhysicalButton::POWER => "power",
+        PhysicalButton::WAKEUP => "wakeup",
+    }
+}
+
+fn parse_button(name: &str) -> Option<PhysicalButton> {
+    Some(match name {
+        "left" => PhysicalButton::LEFT,
+        "middle" => PhysicalButton::MIDDLE,
+        "right" => PhysicalButton::RIGHT,
+        "power" => PhysicalButton::POWER,
+        "wakeup" => PhysicalButton::WAKEUP,
+        _ => return None,
+    })
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pressure::PressureCurve;
+    use crate::touch::TouchMode;
+
+    fn finger(tracking_id: i32, x: u16, y: u16, pressed: bool) -> Finger {
+        Finger {
+            tracking_id,
+            pos: cgmath::Point2::new(x, y),
+            pos_updated: true,
+            last_pressed: true,
+            pressed,
+        }
+    }
+
+    #[test]
+    fn round_trips_events() {
+        let events = [
+            InputEvent::WacomEvent {
+                event: WacomEvent::InstrumentChange {
+                    pen: WacomPen::ToolRubber,
+                    state: true,
+                },
+            },
+            InputEvent::WacomEvent {
+                event: WacomEvent::Draw {
+                    position: cgmath::Point2::new(702.5, 1204.125),
+                    pressure: 1830,
+                    tilt: cgmath::vec2(3, 65532),
+                },
+            },
+            InputEvent::WacomEvent {
+                event: WacomEvent::Hover {
+                    position: cgmath::Point2::new(0.1, 1871.9),
+                    distance: 40,
+                    tilt: cgmath::vec2(0, 0),
+                },
+            },
+            InputEvent::MultitouchEvent {
+                event: MultitouchEvent::Move {
+                    finger: finger(7, 650, 1000, true),
+                },
+            },
+            InputEvent::MultitouchEvent {
+                event: MultitouchEvent::Release {
+                    finger: finger(7, 650, 1000, false),
+                },
+            },
+            InputEvent::GPIO {
+                event: GPIOEvent::Unpress {
+                    button: PhysicalButton::MIDDLE,
+                },
+            },
+        ];
+        let path = std::env::temp_dir().join(format!("harmonizers-{}.rec", std::process::id()));
+        let mut recorder = Recorder::create(&path).unwrap();
+        for event in events.iter() {
+            recorder.record(event).unwrap();
+        }
+        recorder.record(&InputEvent::Unknown {}).unwrap();
+        drop(recorder);
+
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let replayed: Vec<Event> = loaded
+            .iter()
+            .map(|recorded| recorded.event.clone())
+            .collect();
+        let expected: Vec<Event> = events.iter().map(|event| Event::Input(*event)).collect();
+        assert_eq!(replayed, expected);
+        assert!(loaded.windows(2).all(|pair| pair[0].time <= pair[1].time));
+    }
+
+    #[test]
+    fn records_settings_when_they_change() {
+        let mut settings = Settings {
+            brush: "ribbon".to_owned(),
+            draw_mode: DrawMode::Erase(7),
+            touch_mode: TouchMode::FillDiamonds,
+            pressure_curve: PressureCurve::Bezier {
+                x1: 0.1,
+                y1: 0.4,
+                x2: 0.6,
+                y2: 0.9,
+            },
+            draw_velocity: VelocityResponse::NONE,
+            erase_velocity: VelocityResponse::THINNING,
+            stabilizer_enabled: true,
+            stabilizer_strength: 6,
+        };
+        let tap = InputEvent::GPIO {
+            event: GPIOEvent::Press {
+                button: PhysicalButton::RIGHT,
+            },
+        };
+        let path =
+            std::env::temp_dir().join(format!("harmonizers-settings-{}.rec", std::process::id()));
+        let mut recorder = Recorder::create(&path).unwrap();
+        recorder.record_settings(&settings).unwrap();
+        recorder.record(&tap).unwrap();
+        // Unchanged settings aren't written again
+        recorder.record_settings(&settings).unwrap();
+        let first = settings.clone();
+        settings.draw_mode = DrawMode::Draw(3);
+        settings.stabilizer_enabled = false;
+        recorder.record_settings(&settings).unwrap();
+        drop(recorder);
+
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let replayed: Vec<Event> = loaded
+            .iter()
+            .map(|recorded| recorded.event.clone())
+            .collect();
+        assert_eq!(
+            replayed,
+            [
+                Event::Settings(first),
+                Event::Input(tap),
+                Event::Settings(settings)
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips_operations() {
+        let operations = [
+            Operation::Undo,
+            Operation::Redo,
+            Operation::Invert,
+            Operation::Blur,
+            Operation::ZoomOut,
+            Operation::Clear,
+            Operation::Load(PathBuf::from("/home/root/my drawings/cat.hrmz")),
+        ];
+        let path = std::env::temp_dir().join(format!("harmonizers-ops-{}.rec", std::process::id()));
+        let mut recorder = Recorder::create(&path).unwrap();
+        for operation in operations.iter() {
+            recorder.record_operation(operation).unwrap();
+        }
+        drop(recorder);
+
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let replayed: Vec<Event> = loaded
+            .iter()
+            .map(|recorded| recorded.event.clone())
+            .collect();
+        let expected: Vec<Event> = operations.iter().cloned().map(Event::Operation).collect();
+        assert_eq!(replayed, expected);
+        assert_eq!(parse_line("130040 op shred"), None);
+        assert_eq!(parse_line("130040 op load"), None);
+    }
+
+    #[test]
+    fn names_every_pen_and_button() {
+        for pen in [
+            WacomPen::ToolPen,
+            WacomPen::ToolRubber,
+            WacomPen::Touch,
+            WacomPen::Stylus,
+            WacomPen::Stylus2,
+        ] {
+            assert_eq!(parse_pen(pen_name(pen)), Some(pen));
+        }
+        for button in [
+            PhysicalButton::LEFT,
+            PhysicalButton::MIDDLE,
+            PhysicalButton::RIGHT,
+            PhysicalButton::POWER,
+            PhysicalButton::WAKEUP,
+        ] {
+            assert_eq!(parse_button(button_name(button)), Some(button));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(
+            parse_line("130022 button press left").map(|recorded| recorded.event),
+            Some(Event::Input(InputEvent::GPIO {
+                event: GPIOEvent::Press {
+                    button: PhysicalButton::LEFT
+                }
+            }))
+        );
+        assert_eq!(parse_line("130022 button press elbow"), None);
+        assert_eq!(parse_line("130022 pen draw 1 2 3"), None);
+        assert_eq!(parse_line("soon pen instrument pen 1"), None);
+        assert_eq!(
+            parse_line("120502 settings simple sketch 2 None 2 0.5 1 0 off 4 linear"),
+            None
+        );
+    }
+}
@@ -0,0 +1,964 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::cgmath::{EuclideanSpace, InnerSpace};
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::storage;
+use libremarkable::framebuffer::PartialRefreshMode;
+use libremarkable::framebuffer::{FramebufferIO, FramebufferRefresh};
+use libremarkable::image::{self, GenericImage};
+use libremarkable::input::{
+    GPIOEvent, InputEvent, MultitouchEvent, PhysicalButton, WacomEvent, WacomPen,
+};
+
+use log::warn;
+
+use std::collections::VecDeque;
+use std::io;
+use std::time::Instant;
+
+use crate::brushes::{Brush, BrushRegistry, PenSample, StrokeStyle};
+use crate::document::{self, Document};
+use crate::drawings::Drawing;
+use crate::headless::Surface;
+use crate::history::{self, History};
+use crate::pressure::{PressureCurve, VelocityResponse};
+use crate::recording::{Event, Operation, Recorded};
+use crate::stabilizer::Stabilizer;
+use crate::touch::TouchMode;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DrawMode {
+    Draw(u32),
+    Erase(u32),
+}
+impl DrawMode {
+    pub fn set_size(self, new_size: u32) -> Self {
+        match self {
+            DrawMode::Draw(_) => DrawMode::Draw(new_size),
+            DrawMode::Erase(_) => DrawMode::Erase(new_size),
+        }
+    }
+    pub fn color_as_string(self) -> String {
+        match self {
+            DrawMode::Draw(_) => "Black",
+            DrawMode::Erase(_) => "White",
+        }
+        .into()
+    }
+    pub fn get_size(self) -> u32 {
+        match self {
+            DrawMode::Draw(s) => s,
+            DrawMode::Erase(s) => s,
+        }
+    }
+}
+
+/// What the app's controls set on a session, recorded along with the input so
+/// replays draw with the same pen.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub brush: String,
+    pub draw_mode: DrawMode,
+    pub touch_mode: TouchMode,
+    pub pressure_curve: PressureCurve,
+    pub draw_velocity: VelocityResponse,
+    pub erase_velocity: VelocityResponse,
+    pub stabilizer_enabled: bool,
+    pub stabilizer_strength: u32,
+}
+
+/// Where the harmonizers app puts its canvas: the screen below its controls.
+///
+/// This region will have the following size at rest:
+///   raw: 5896 kB
+///   zstd: 10 kB
+pub const CANVAS_REGION: mxcfb_rect = mxcfb_rect {
+    top: 720,
+    left: 0,
+    height: 1080,
+    width: 1404,
+};
+
+type SavedRegion = (mxcfb_rect, Vec<u8>);
+
+/// How far ahead of the newest sample the tail is extrapolated
+const PREDICTION_MILLIS: f32 = 30.0;
+/// Longest tail ever predicted, in pixels
+const PREDICTION_MAX_LENGTH: f32 = 40.0;
+
+/// Everything needed to draw on one canvas: the pen settings, the stroke in
+/// progress, the strokes drawn so far and their undo history.
+///
+/// A session draws on whichever framebuffer its methods are given, within the
+/// canvas region it was created for, and shares nothing with other sessions.
+pub struct Session {
+    pub draw_mode: DrawMode,
+    pub touch_mode: TouchMode,
+    pub pressure_curve: PressureCurve,
+    pub draw_velocity: VelocityResponse,
+    pub erase_velocity: VelocityResponse,
+    pub stabilizer_enabled: bool,
+    pub stabilizer: Stabilizer,
+    /// Whether brushes that trail behind the pen get a predicted tail
+    pub predict_tail: bool,
+    region: mxcfb_rect,
+    registry: BrushRegistry,
+    brush: Box<dyn Brush>,
+    brush_index: usize,
+    /// The latest samples of the pen stroke in progress
+    pen_samples: VecDeque<PenSample>,
+    /// Region covered by the predicted tail and the pixels it was drawn over
+    predicted_tail: Option<SavedRegion>,
+    unpress_observed: bool,
+    pen_in_range: bool,
+    rubber_side: bool,
+    document: Document,
+    history: History,
+}
+
+impl Session {
+    /// A session drawing inside `region` with the default pen settings.
+    pub fn new(region: mxcfb_rect) -> Self {
+        let registry = BrushRegistry::default();
+        let brush = registry.create_at(0).unwrap();
+        Session {
+            draw_mode: DrawMode::Draw(2),
+            touch_mode: TouchMode::OnlyUI,
+            pressure_curve: PressureCurve::default(),
+            // Fast strokes come out thinner when drawing, erasing keeps an even width
+            draw_velocity: VelocityResponse::THINNING,
+            erase_velocity: VelocityResponse::NONE,
+            stabilizer_enabled: false,
+            stabilizer: Stabilizer::new(4),
+            predict_tail: true,
+            region,
+            registry,
+            brush,
+            brush_index: 0,
+            pen_samples: VecDeque::new(),
+            predicted_tail: None,
+            unpress_observed: false,
+            pen_in_range: false,
+            rubber_side: false,
+            document: Document::new(),
+            history: History::new(region, history::DEFAULT_BUDGET),
+        }
+    }
+
+    pub fn region(&self) -> mxcfb_rect {
+        self.region
+    }
+
+    pub fn registry(&self) -> &BrushRegistry {
+        &self.registry
+    }
+
+    pub fn document(&self) -> &Document {
+        &self.document
+    }
+
+    /// Index of the pen brush in the registry.
+    pub fn brush_index(&self) -> usize {
+        self.brush_index
+    }
+
+    /// How strokes of the current draw mode respond to pen speed.
+    pub fn velocity(&self) -> VelocityResponse {
+        match self.draw_mode {
+            DrawMode::Draw(_) => self.draw_velocity,
+            DrawMode::Erase(_) => self.erase_velocity,
+        }
+    }
+
+    /// Sets the speed response of the current draw mode, the other one keeps its own.
+    pub fn set_velocity(&mut self, velocity: VelocityResponse) {
+        match self.draw_mode {
+            DrawMode::Draw(_) => self.draw_velocity = velocity,
+            DrawMode::Erase(_) => self.erase_velocity = velocity,
+        }
+    }
+
+    /// Switches the pen to the brush registered under `name`, returning
+    /// whether there is one.
+    pub fn select_brush(&mut self, name: &str) -> bool {
+        let index = match self.registry.index_of(name) {
+            Some(index) => index,
+            None => return false,
+        };
+        self.brush = self.registry.create_at(index).unwrap();
+        self.brush_index = index;
+        true
+    }
+
+    pub fn settings(&self) -> Settings {
+        Settings {
+            brush: self.brush.name().to_owned(),
+            draw_mode: self.draw_mode,
+            touch_mode: self.touch_mode,
+            pressure_curve: self.pressure_curve,
+            draw_velocity: self.draw_velocity,
+            erase_velocity: self.erase_velocity,
+            stabilizer_enabled: self.stabilizer_enabled,
+            stabilizer_strength: self.stabilizer.strength(),
+        }
+    }
+
+    /// Takes on the given settings. The brush stays as it is when there's none
+    /// registered under the name.
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        if settings.brush != self.brush.name() {
+            self.select_brush(&settings.brush);
+        }
+        self.draw_mode = settings.draw_mode;
+        self.touch_mode = settings.touch_mode;
+        self.pressure_curve = settings.pressure_curve;
+        self.draw_velocity = settings.draw_velocity;
+        self.erase_velocity = settings.erase_velocity;
+        self.stabilizer_enabled = settings.stabilizer_enabled;
+        self.stabilizer.set_strength(settings.stabilizer_strength);
+    }
+
+    /// Whether the pen is close enough to the screen to be reported.
+    pub fn pen_in_range(&self) -> bool {
+        self.pen_in_range
+    }
+
+    /// Starts over with an empty document and takes whatever is on the canvas
+    /// now as the start of the undo history.
+    pub fn reset(&mut self, fb: &dyn FramebufferIO) -> io::Result<()> {
+        self.document.clear();
+        self.history.reset(fb, &self.document)
+    }
+
+    /// Handles a pen event at `time`.
+    ///
+    /// Lifting the pen and putting it down again outside the canvas is a tap,
+    /// so the pen can press the buttons around it. Its position gets returned
+    /// for the app to find what was tapped.
+    pub fn wacom_input(
+        &mut self,
+        fb: &mut impl Surface,
+        input: WacomEvent,
+        time: Instant,
+    ) -> Option<cgmath::Point2<f32>> {
+        match input {
+            WacomEvent::Draw {
+                position,
+                pressure,
+                tilt,
+            } => {
+                if !self.region.contains_point(&position.cast().unwrap()) {
+                    self.end_pen_stroke(fb);
+                    if std::mem::take(&mut self.unpress_observed) {
+                        return Some(position);
+                    }
+                    return None;
+                }
+
+                let (mut col, mut mult) = match self.draw_mode {
+                    DrawMode::Draw(s) => (color::BLACK, s),
+                    DrawMode::Erase(s) => (color::WHITE, s * 3),
+                };
+                if self.rubber_side {
+                    col = match col {
+                        color::WHITE => color::BLACK,
+                        _ => color::WHITE,
+                    };
+                    mult = 50; // Rough size of the rubber end
+                }
+                let style = StrokeStyle {
+                    color: col,
+                    size: mult,
+                    pressure_curve: self.pressure_curve,
+                    velocity: self.velocity(),
+                    clip: self.region,
+                };
+
+                let position = if self.stabilizer_enabled {
+                    // Nothing happens until the pen moves far enough to pull the stroke along
+                    self.stabilizer.filter(position.cast().unwrap())?
+                } else {
+                    position.cast().unwrap()
+                };
+                let sample = PenSample {
+                    position,
+                    pressure: pressure as i32,
+                    // The digitizer reports negative tilt wrapped around
+                    tilt: cgmath::vec2(tilt.x as i16, tilt.y as i16),
+                    time,
+                };
+                if self.pen_samples.is_empty() {
+                    // A stroke still settling from before is an edit of its own
+                    self.record_stroke_edit(fb);
+                }
+                self.pen_samples.push_back(sample);
+
+                // The real stroke replaces the previous guess
+                let mut rect = self.clear_predicted_tail(fb);
+                rect = rect.merge_rect(&if self.pen_samples.len() == 1 {
+                    self.document.begin_stroke(self.brush.name(), style, sample);
+                    self.brush.begin_stroke(fb, sample, style)
+                } else {
+                    self.document.add_point(sample);
+                    self.brush.stroke_to(fb, &self.pen_samples, style)
+                });
+                if self.brush.trails_pen() && self.predict_tail {
+                    if let Some((tail_rect, pixels)) =
+                        draw_predicted_tail(fb, &self.pen_samples, style, self.region)
+                    {
+                        rect = rect.merge_rect(&tail_rect);
+                        self.predicted_tail = Some((tail_rect, pixels));
+                    }
+                }
+                // Brushes only ever look at the last three samples
+                while self.pen_samples.len() >= 3 {
+                    self.pen_samples.pop_front();
+                }
+                self.history.mark_dirty(&rect);
+                refresh_pen_region(fb, &rect, self.brush.uses_gray());
+            }
+            WacomEvent::InstrumentChange { pen, state } => {
+                match pen {
+                    // Whether the pen is in range
+                    WacomPen::ToolPen => {
+                        self.pen_in_range = state;
+                        self.rubber_side = false;
+                    }
+                    WacomPen::ToolRubber => {
+                        self.pen_in_range = state;
+                        self.rubber_side = true;
+                    }
+                    // Whether the pen is actually making contact. Stop drawing
+                    // when instrument has left the vicinity of the screen
+                    WacomPen::Touch if !state => self.end_pen_stroke(fb),
+                    // Touching down and other instruments don't need handling
+                    _ => {}
+                }
+            }
+            WacomEvent::Hover {
+                position: _,
+                distance,
+                tilt: _,
+            } => {
+                // If the pen is hovering, don't record its coordinates as the origin of the next line
+                if distance > 1 {
+                    self.end_pen_stroke(fb);
+                    self.unpress_observed = true;
+                }
+            }
+            _ => {}
+        };
+        None
+    }
+
+    /// Handles a finger on the canvas at `time`, stamping the touch mode's
+    /// shape where it presses and moves.
+    pub fn touch_input(&mut self, fb: &mut impl Surface, input: MultitouchEvent, time: Instant) {
+        let (finger, press) = match input {
+            MultitouchEvent::Press { finger } => (finger, true),
+            MultitouchEvent::Move { finger } => (finger, false),
+            MultitouchEvent::Release { .. } => {
+                self.record_stroke_edit(fb);
+                return;
+            }
+            _ => return,
+        };
+        if !self.region.contains_point(&finger.pos.cast().unwrap()) {
+            return;
+        }
+        let position = finger.pos.cast().unwrap();
+        let rect = match self.touch_mode.draw_stamp(fb, position) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let style = StrokeStyle {
+            color: color::BLACK,
+            size: 0,
+            pressure_curve: PressureCurve::Linear,
+            velocity: VelocityResponse::NONE,
+            clip: self.region,
+        };
+        let sample = PenSample {
+            position,
+            pressure: 0,
+            tilt: cgmath::vec2(0, 0),
+            time,
+        };
+        self.document.stamp(self.touch_mode, style, sample, press);
+        self.history.mark_dirty(&rect);
+        fb.partial_refresh(
+            &rect,
+            PartialRefreshMode::Async,
+            waveform_mode::WAVEFORM_MODE_DU,
+            display_temp::TEMP_USE_REMARKABLE_DRAW,
+            dither_mode::EPDC_FLAG_USE_DITHERING_ALPHA,
+            DRAWING_QUANT_BIT,
+            false,
+        );
+    }
+
+    /// Steps an animated brush by a frame. Apps call this every
+    /// `document::TICK_INTERVAL`.
+    pub fn tick(&mut self, fb: &mut impl Surface) {
+        if self.brush.is_animating() {
+            let rect = self.brush.tick(fb);
+            self.history.mark_dirty(&rect);
+            if self.pen_samples.is_empty() && !self.brush.is_animating() {
+                self.record_stroke_edit(fb);
+            }
+            refresh_pen_region(fb, &rect, self.brush.uses_gray());
+        }
+    }
+
+    /// Feeds recorded input through the session.
+    ///
+    /// Events are handled as fast as possible, but pen samples are stamped with
+    /// the time of their event and animated brushes step as many frames as
+    /// passed in between, so the same events always draw the same image. Like
+    /// in the app, the power button ends the replay. Recorded settings and
+    /// canvas operations take the place of the buttons around the canvas, taps
+    /// outside it are ignored. Drawings are loaded from where they were saved,
+    /// so they need to still be there.
+    pub fn replay(&mut self, fb: &mut impl Surface, events: &[Recorded]) {
+        let start = Instant::now();
+        let mut frame = 0;
+        for recorded in events.iter() {
+            let until =
+                (recorded.time.as_secs_f32() / document::TICK_INTERVAL.as_secs_f32()) as u32;
+            while frame < until {
+                self.tick(fb);
+                frame += 1;
+            }
+            let time = start + recorded.time;
+            let input = match recorded.event {
+                Event::Input(input) => input,
+                Event::Settings(ref settings) => {
+                    self.apply_settings(settings);
+                    continue;
+                }
+                Event::Operation(ref operation) => {
+                    if let Err(err) = self.apply_operation(fb, operation) {
+                        warn!("Failed to replay {0:?}: {1}", operation, err);
+                    }
+                    continue;
+                }
+            };
+            match input {
+                InputEvent::WacomEvent { event } => {
+                    self.wacom_input(fb, event, time);
+                }
+                InputEvent::MultitouchEvent { event } => self.touch_input(fb, event, time),
+                // Presses while the pen is in range are accidental. The clears
+                // of the other buttons got recorded as operations.
+                InputEvent::GPIO {
+                    event:
+                        GPIOEvent::Press {
+                            button: PhysicalButton::POWER,
+                        },
+                } if !self.pen_in_range => break,
+                _ => {}
+            }
+        }
+    }
+
+    fn apply_operation(&mut self, fb: &mut impl Surface, operation: &Operation) -> io::Result<()> {
+        match operation {
+            Operation::Undo => self.undo(fb),
+            Operation::Redo => self.redo(fb),
+            Operation::Invert => self.invert(fb),
+            Operation::Blur => self.blur(fb),
+            Operation::ZoomOut => self.zoom_out(fb),
+            Operation::Clear => {
+                fb.clear();
+                self.reset(fb)
+            }
+            Operation::Load(path) => self.load_drawing(fb, &Drawing::load(path)?),
+        }
+    }
+
+    /// Records a change the app made to the pixels of the whole canvas, so it
+    /// can be undone.
+    pub fn record_canvas(&mut self, fb: &dyn FramebufferIO) -> io::Result<()> {
+        self.history.record_canvas(fb)
+    }
+
+    /// Shrinks the canvas contents to 80% around its center.
+    pub fn zoom_out(&mut self, fb: &mut impl Surface) -> io::Result<()> {
+        let region = self.region;
+        let buff = fb.dump_region(region).map_err(io::Error::other)?;
+        let resized = image::DynamicImage::ImageRgb8(
+            storage::rgbimage_from_u8_slice(region.width, region.height, buff.as_slice()).unwrap(),
+        )
+        .resize(
+            (region.width as f32 / 1.25f32) as u32,
+            (region.height as f32 / 1.25f32) as u32,
+            image::imageops::Nearest,
+        );
+
+        // Get a clean image the size of the canvas
+        let mut new_image = image::DynamicImage::new_rgb8(region.width, region.height);
+        new_image.invert();
+
+        // Copy the resized image into the subimage
+        new_image
+            .copy_from(&resized, region.width / 8, region.height / 8)
+            .unwrap();
+
+        fb.draw_image(
+            new_image.as_rgb8().unwrap(),
+            region.top_left().cast().unwrap(),
+        );
+        refresh_canvas(fb, &region);
+        self.record_canvas(fb)
+    }
+
+    /// Softens the canvas with a slight gaussian blur.
+    pub fn blur(&mut self, fb: &mut impl Surface) -> io::Result<()> {
+        let region = self.region;
+        let buff = fb.dump_region(region).map_err(io::Error::other)?;
+        let dynamic = image::DynamicImage::ImageRgb8(
+            storage::rgbimage_from_u8_slice(region.width, region.height, buff.as_slice()).unwrap(),
+        )
+        .blur(0.6f32);
+
+        fb.draw_image(
+            dynamic.as_rgb8().unwrap(),
+            region.top_left().cast().unwrap(),
+        );
+        refresh_canvas(fb, &region);
+        self.record_canvas(fb)
+    }
+
+    /// Turns black into white and white into black across the canvas.
+    pub fn invert(&mut self, fb: &mut impl Surface) -> io::Result<()> {
+        let mut buff = fb.dump_region(self.region).map_err(io::Error::other)?;
+        buff.iter_mut().for_each(|p| {
+            *p = !(*p);
+        });
+        fb.restore_region(self.region, &buff)
+            .map_err(io::Error::other)?;
+        refresh_canvas(fb, &self.region);
+        self.record_canvas(fb)
+    }
+
+    /// Replaces the canvas and its strokes with a saved drawing.
+    pub fn load_drawing(&mut self, fb: &mut impl Surface, drawing: &Drawing) -> io::Result<()> {
+        if drawing.width != self.region.width || drawing.height != self.region.height {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The drawing is {0}x{1}, the canvas is {2}x{3}",
+                    drawing.width, drawing.height, self.region.width, self.region.height
+                ),
+            ));
+        }
+        if drawing.bytes_per_pixel as usize != self.history.bytes_per_pixel() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The drawing has {0} bytes per pixel, the canvas {1}",
+                    drawing.bytes_per_pixel,
+                    self.history.bytes_per_pixel()
+                ),
+            ));
+        }
+        fb.restore_region(self.region, &drawing.pixels)
+            .map_err(io::Error::other)?;
+        refresh_canvas(fb, &self.region);
+        let before = std::mem::replace(&mut self.document, drawing.document.clone());
+        self.history
+            .record_document(fb, before, drawing.document.clone())
+    }
+
+    /// Reverts the latest edit.
+    pub fn undo(&mut self, fb: &mut impl Surface) -> io::Result<()> {
+        if let Some(rect) = self.history.undo(fb, &mut self.document)? {
+            refresh_canvas(fb, &rect);
+        }
+        Ok(())
+    }
+
+    /// Applies the latest undone edit again.
+    pub fn redo(&mut self, fb: &mut impl Surface) -> io::Result<()> {
+        if let Some(rect) = self.history.redo(fb, &mut self.document)? {
+            refresh_canvas(fb, &rect);
+        }
+        Ok(())
+    }
+
+    /// Records the strokes finished since the last edit for undo.
+    fn record_stroke_edit(&mut self, fb: &dyn FramebufferIO) {
+        if let Err(err) = self.history.record_strokes(fb, &self.document) {
+            warn!("Failed to record edit: {0}", err);
+        }
+    }
+
+    /// Forgets the samples of the current stroke and lets the active brush finish it.
+    fn end_pen_stroke(&mut self, fb: &mut impl Surface) {
+        self.stabilizer.reset();
+        self.document.end_stroke();
+        if self.pen_samples.is_empty() {
+            return;
+        }
+        self.pen_samples.clear();
+        let tail_rect = self.clear_predicted_tail(fb);
+        let rect = self.brush.end_stroke(fb).merge_rect(&tail_rect);
+        self.history.mark_dirty(&rect);
+        // An animated brush keeps drawing after the pen lifts, its stroke gets
+        // recorded once it settles
+        if !self.brush.is_animating() {
+            self.record_stroke_edit(fb);
+        }
+        refresh_pen_region(fb, &rect, self.brush.uses_gray());
+    }
+
+    /// Puts back what was under the predicted tail, returning the region to refresh.
+    fn clear_predicted_tail(&mut self, fb: &mut dyn FramebufferIO) -> mxcfb_rect {
+        match self.predicted_tail.take() {
+            None => mxcfb_rect::invalid(),
+            Some((rect, pixels)) => match fb.restore_region(rect, &pixels) {
+                Err(e) => {
+                    warn!("Error while restoring region: {0}", e);
+                    mxcfb_rect::invalid()
+                }
+                Ok(_) => rect,
+            },
+        }
+    }
+}
+
+fn refresh_canvas(fb: &dyn FramebufferRefresh, rect: &mxcfb_rect) {
+    fb.partial_refresh(
+        rect,
+        PartialRefreshMode::Async,
+        waveform_mode::WAVEFORM_MODE_GC16_FAST,
+        display_temp::TEMP_USE_REMARKABLE_DRAW,
+        dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
+        0,
+        false,
+    );
+}
+
+fn refresh_pen_region(fb: &dyn FramebufferRefresh, rect: &mxcfb_rect, gray: bool) {
+    if *rect == mxcfb_rect::invalid() {
+        return;
+    }
+    if gray {
+        refresh_canvas(fb, rect);
+    } else {
+        fb.partial_refresh(
+            rect,
+            PartialRefreshMode::Async,
+            waveform_mode::WAVEFORM_MODE_DU,
+            display_temp::TEMP_USE_REMARKABLE_DRAW,
+            dither_mode::EPDC_FLAG_EXP1,
+            DRAWING_QUANT_BIT,
+            false,
+        );
+    }
+}
+
+/// Extrapolates the stroke from the velocity of the last two samples and draws
+/// the part the brush hasn't caught up with yet, returning its region along
+/// with the pixels that were under it so they can be put back once the real
+/// samples arrive.
+fn draw_predicted_tail(
+    fb: &mut impl Surface,
+    pen_samples: &VecDeque<PenSample>,
+    style: StrokeStyle,
+    region: mxcfb_rect,
+) -> Option<SavedRegion> {
+    if pen_samples.len() < 2 {
+        return None;
+    }
+    let newest = pen_samples[pen_samples.len() - 1];
+    let prev = pen_samples[pen_samples.len() - 2];
+    let direction = newest.position - prev.position;
+    if direction.magnitude2() == 0.0 {
+        return None;
+    }
+    let length = (newest.speed_since(&prev) * PREDICTION_MILLIS).min(PREDICTION_MAX_LENGTH);
+    let predicted = newest.position + direction.normalize() * length;
+    let start = prev.position.midpoint(newest.position);
+
+    let width = (style.pressure_curve.radius(style.size, newest.pressure) * 2.0).max(1.0);
+    let margin = width / 2.0 + 1.0;
+    let left = start.x.min(newest.position.x).min(predicted.x) - margin;
+    let top = start.y.min(newest.position.y).min(predicted.y) - margin;
+    let right = start.x.max(newest.position.x).max(predicted.x) + margin;
+    let bottom = start.y.max(newest.position.y).max(predicted.y) + margin;
+    // Only what's within the canvas gets put back, so a tail reaching past it
+    // would be left behind on the UI around it
+    if left < region.left as f32
+        || top < region.top as f32
+        || right > (region.left + region.width) as f32
+        || bottom > (region.top + region.height) as f32
+    {
+        return None;
+    }
+    let rect = mxcfb_rect {
+        top: top as u32,
+        left: left as u32,
+        width: right as u32 - left as u32,
+        height: bottom as u32 - top as u32,
+    };
+
+    let pixels = match fb.dump_region(rect) {
+        Err(err) => {
+            warn!("Failed to dump buffer: {0}", err);
+            return None;
+        }
+        Ok(pixels) => pixels,
+    };
+    fb.draw_line(
+        start.cast().unwrap(),
+        newest.position.cast().unwrap(),
+        width as u32,
+        style.color,
+    );
+    fb.draw_line(
+        newest.position.cast().unwrap(),
+        predicted.cast().unwrap(),
+        width as u32,
+        style.color,
+    );
+    Some((rect, pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::StrokeKind;
+    use crate::headless::HeadlessFramebuffer;
+    use std::time::Duration;
+
+    const REGION: mxcfb_rect = mxcfb_rect {
+        top: 0,
+        left: 0,
+        width: 64,
+        height: 64,
+    };
+
+    fn draw(position: (f32, f32)) -> WacomEvent {
+        WacomEvent::Draw {
+            position: cgmath::Point2::new(position.0, position.1),
+            pressure: 2000,
+            tilt: cgmath::vec2(0, 0),
+        }
+    }
+
+    #[test]
+    fn replays_recorded_settings() {
+        let mut session = Session::new(REGION);
+        let mut fb = HeadlessFramebuffer::new(64, 64);
+        session.reset(&fb).unwrap();
+        let settings = Settings {
+            brush: "sketchy".to_owned(),
+            draw_mode: DrawMode::Erase(5),
+            stabilizer_enabled: true,
+            stabilizer_strength: 2,
+            ..session.settings()
+        };
+        let recorded = |time: u64, event: Event| Recorded {
+            time: Duration::from_millis(time),
+            event,
+        };
+        let mut events = vec![recorded(0, Event::Settings(settings.clone()))];
+        for (i, x) in [10.0, 30.0, 50.0].iter().enumerate() {
+            let input = InputEvent::WacomEvent {
+                event: draw((*x, 32.0)),
+            };
+            events.push(recorded(i as u64 * 10, Event::Input(input)));
+        }
+        session.replay(&mut fb, &events);
+
+        assert_eq!(session.settings(), settings);
+        let stroke = &session.document().strokes[0];
+        assert_eq!(
+            stroke.kind,
+            StrokeKind::Pen {
+                brush: "sketchy".to_owned()
+            }
+        );
+        assert_eq!(stroke.style.color.to_rgb8(), color::WHITE.to_rgb8());
+    }
+
+    #[test]
+    fn replays_canvas_operations() {
+        let mut session = Session::new(REGION);
+        let mut fb = HeadlessFramebuffer::new(64, 64);
+        session.reset(&fb).unwrap();
+        let blank = fb.dump_region(REGION).unwrap();
+        let recorded = |event: Event| Recorded {
+            time: Duration::ZERO,
+            event,
+        };
+        let lift = WacomEvent::InstrumentChange {
+            pen: WacomPen::Touch,
+            state: false,
+        };
+        let stroke: Vec<Recorded> = [
+            draw((10.0, 32.0)),
+            draw((30.0, 32.0)),
+            draw((50.0, 32.0)),
+            lift,
+        ]
+        .iter()
+        .map(|event| recorded(Event::Input(InputEvent::WacomEvent { event: *event })))
+        .collect();
+        session.replay(&mut fb, &stroke);
+        let drawn = fb.dump_region(REGION).unwrap();
+        assert_ne!(drawn, blank);
+        let saved = std::env::temp_dir().join(format!("harmonizers-replay-{}", std::process::id()));
+        Drawing::new(64, 64, drawn.clone(), session.document().clone())
+            .save(&saved)
+            .unwrap();
+
+        session.replay(&mut fb, &[recorded(Event::Operation(Operation::Invert))]);
+        assert_ne!(fb.dump_region(REGION).unwrap(), drawn);
+        session.replay(&mut fb, &[recorded(Event::Operation(Operation::Undo))]);
+        assert_eq!(fb.dump_region(REGION).unwrap(), drawn);
+
+        session.replay(&mut fb, &[recorded(Event::Operation(Operation::Clear))]);
+        assert_eq!(fb.dump_region(REGION).unwrap(), blank);
+        assert!(session.document().strokes.is_empty());
+
+        session.replay(
+            &mut fb,
+            &[recorded(Event::Operation(Operation::Load(saved.clone())))],
+        );
+        std::fs::remove_file(&saved).unwrap();
+        assert_eq!(fb.dump_region(REGION).unwrap(), drawn);
+        assert_eq!(session.document().strokes.len(), 1);
+    }
+
+    #[test]
+    fn selects_registered_brushes() {
+        let mut session = Session::new(REGION);
+        assert_eq!(session.settings().brush, "simple");
+
+        assert!(session.select_brush("web"));
+        assert_eq!(
+            session.brush_index(),
+            session.registry().index_of("web").unwrap()
+        );
+        assert_eq!(session.settings().brush, "web");
+
+        assert!(!session.select_brush("missing"));
+        assert_eq!(session.settings().brush, "web");
+    }
+
+    #[test]
+    fn velocity_follows_draw_mode() {
+        let mut session = Session::new(REGION);
+        session.set_velocity(VelocityResponse::NONE);
+        assert_eq!(session.draw_velocity, VelocityResponse::NONE);
+
+        session.draw_mode = DrawMode::Erase(2);
+        assert_eq!(session.velocity(), VelocityResponse::NONE);
+        session.set_velocity(VelocityResponse::THINNING);
+        assert_eq!(session.erase_velocity, VelocityResponse::THINNING);
+        assert_eq!(session.draw_velocity, VelocityResponse::NONE);
+    }
+
+    #[test]
+    fn animated_strokes_are_recorded_once_they_settle() {
+        let mut session = Session::new(REGION);
+        assert!(session.select_brush("ribbon"));
+        let mut fb = HeadlessFramebuffer::new(64, 64);
+        session.reset(&fb).unwrap();
+        let blank = fb.dump_region(REGION).unwrap();
+
+        let time = Instant::now();
+        for x in [10.0, 30.0, 50.0] {
+            session.wacom_input(&mut fb, draw((x, 32.0)), time);
+            session.tick(&mut fb);
+        }
+        let lift = WacomEvent::InstrumentChange {
+            pen: WacomPen::Touch,
+            state: false,
+        };
+        session.wacom_input(&mut fb, lift, time);
+        let lifted = fb.dump_region(REGION).unwrap();
+        for _ in 0..200 {
+            session.tick(&mut fb);
+        }
+        assert_ne!(fb.dump_region(REGION).unwrap(), lifted);
+
+        // What it drew after the lift belongs to the same edit
+        session.undo(&mut fb).unwrap();
+        assert_eq!(fb.dump_region(REGION).unwrap(), blank);
+    }
+
+    #[test]
+    fn predicted_tail_stays_on_the_canvas() {
+        // The canvas starts below a strip of UI
+        let region = mxcfb_rect {
+            top: 16,
+            left: 0,
+            width: 128,
+            height: 48,
+        };
+        let ui = mxcfb_rect {
+            top: 0,
+            left: 0,
+            width: 128,
+            height: 16,
+        };
+        let mut session = Session::new(region);
+        session.draw_mode = DrawMode::Draw(10);
+        let mut fb = HeadlessFramebuffer::new(128, 64);
+        session.reset(&fb).unwrap();
+        let blank = fb.dump_region(ui).unwrap();
+        let blank_canvas = fb.dump_region(region).unwrap();
+
+        // Fast along the top edge, where the stroke thins out but its
+        // predicted tail doesn't
+        let start = Instant::now();
+        for (i, x) in [10.0, 20.0, 30.0, 40.0, 50.0].iter().enumerate() {
+            let time = start + Duration::from_millis(i as u64);
+            session.wacom_input(&mut fb, draw((*x, 19.0)), time);
+            assert_eq!(fb.dump_region(ui).unwrap(), blank);
+        }
+        let lift = WacomEvent::InstrumentChange {
+            pen: WacomPen::Touch,
+            state: false,
+        };
+        session.wacom_input(&mut fb, lift, start + Duration::from_millis(5));
+        assert_eq!(fb.dump_region(ui).unwrap(), blank);
+        assert_ne!(fb.dump_region(region).unwrap(), blank_canvas);
+    }
+
+    #[test]
+    fn sessions_keep_to_themselves() {
+        let mut first = Session::new(REGION);
+        let mut first_fb = HeadlessFramebuffer::new(64, 64);
+        let mut second = Session::new(REGION);
+        let mut second_fb = HeadlessFramebuffer::new(64, 64);
+        first.reset(&first_fb).unwrap();
+        second.reset(&second_fb).unwrap();
+        let blank = second_fb.dump_region(REGION).unwrap();
+
+        first.draw_mode = DrawMode::Draw(6);
+        let time = Instant::now();
+        for x in [10.0, 30.0, 50.0] {
+            first.wacom_input(&mut first_fb, draw((x, 32.0)), time);
+        }
+        // Only a stroke in progress on the second canvas could be ended here
+        second.wacom_input(
+            &mut second_fb,
+            WacomEvent::InstrumentChange {
+                pen: WacomPen::Touch,
+                state: false,
+            },
+            time,
+        );
+        first.wacom_input(&mut first_fb, draw((60.0, 40.0)), time);
+
+        assert_eq!(first.document().strokes.len(), 1);
+        assert_eq!(first.document().strokes[0].points.len(), 4);
+        assert!(second.document().is_empty());
+        assert_eq!(second.draw_mode, DrawMode::Draw(2));
+        assert_ne!(first_fb.dump_region(REGION).unwrap(), blank);
+        assert_eq!(second_fb.dump_region(REGION).unwrap(), blank);
+    }
+}
@@ -0,0 +1,135 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::cgmath::InnerSpace;
+
+/// Lazy-mouse smoothing: the stroke is pulled along by the pen on a string, so
+/// jitter shorter than the string never reaches the canvas.
+pub struct Stabilizer {
+    strength: u32,
+    anchor: Option<cgmath::Point2<f32>>,
+}
+
+impl Stabilizer {
+    pub const MIN_STRENGTH: u32 = 1;
+    pub const MAX_STRENGTH: u32 = 10;
+
+    /// String length in pixels per step of strength.
+    const STRING_PER_STRENGTH: f32 = 4.0;
+
+    pub fn new(strength: u32) -> Self {
+        Stabilizer {
+            strength: strength.clamp(Stabilizer::MIN_STRENGTH, Stabilizer::MAX_STRENGTH),
+            anchor: None,
+        }
+    }
+
+    pub fn strength(&self) -> u32 {
+        self.strength
+    }
+
+    pub fn set_strength(&mut self, strength: u32) {
+        self.strength = strength.clamp(Stabilizer::MIN_STRENGTH, Stabilizer::MAX_STRENGTH);
+    }
+
+    /// Forgets the current stroke so the next one starts right at the pen.
+    pub fn reset(&mut self) {
+        self.anchor = None;
+    }
+
+    /// Moves the end of the string towards `position` and returns where it ended
+    /// up, or `None` while the pen hasn't pulled the string taut.
+    pub fn filter(&mut self, position: cgmath::Point2<f32>) -> Option<cgmath::Point2<f32>> {
+        let anchor = match self.anchor {
+            Some(anchor) => anchor,
+            None => {
+                self.anchor = Some(position);
+                return Some(position);
+            }
+        };
+
+        let length = self.strength as f32 * Stabilizer::STRING_PER_STRENGTH;
+        let pull = position - anchor;
+        let distance = pull.magnitude();
+        if distance <= length {
+            return None;
+        }
+        let anchor = anchor + pull * ((distance - length) / distance);
+        self.anchor = Some(anchor);
+        Some(anchor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f32, y: f32) -> cgmath::Point2<f32> {
+        cgmath::Point2 { x, y }
+    }
+
+    fn assert_close(actual: cgmath::Point2<f32>, expected: cgmath::Point2<f32>) {
+        assert!(
+            (actual - expected).magnitude() < 1e-3,
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn first_sample_passes_through() {
+        let mut stabilizer = Stabilizer::new(4);
+        assert_eq!(
+            stabilizer.filter(point(10.0, 20.0)),
+            Some(point(10.0, 20.0))
+        );
+    }
+
+    #[test]
+    fn slack_swallows_jitter() {
+        // A string of 16 pixels
+        let mut stabilizer = Stabilizer::new(4);
+        stabilizer.filter(point(0.0, 0.0));
+        assert_eq!(stabilizer.filter(point(10.0, 0.0)), None);
+        assert_eq!(stabilizer.filter(point(0.0, 16.0)), None);
+        assert_eq!(stabilizer.filter(point(-5.0, -5.0)), None);
+    }
+
+    #[test]
+    fn pulls_to_string_length_behind_pen() {
+        let mut stabilizer = Stabilizer::new(4);
+        stabilizer.filter(point(0.0, 0.0));
+        assert_close(
+            stabilizer.filter(point(20.0, 0.0)).unwrap(),
+            point(4.0, 0.0),
+        );
+        // The string hangs from where it was pulled to, not from the first sample
+        assert_eq!(stabilizer.filter(point(10.0, 0.0)), None);
+        assert_close(
+            stabilizer.filter(point(4.0, 30.0)).unwrap(),
+            point(4.0, 14.0),
+        );
+    }
+
+    #[test]
+    fn reset_starts_at_pen() {
+        let mut stabilizer = Stabilizer::new(4);
+        stabilizer.filter(point(0.0, 0.0));
+        stabilizer.reset();
+        assert_eq!(stabilizer.filter(point(5.0, 5.0)), Some(point(5.0, 5.0)));
+        assert_eq!(stabilizer.filter(point(10.0, 5.0)), None);
+    }
+
+    #[test]
+    fn strength_is_clamped() {
+        assert_eq!(Stabilizer::new(0).strength(), Stabilizer::MIN_STRENGTH);
+        assert_eq!(Stabilizer::new(50).strength(), Stabilizer::MAX_STRENGTH);
+
+        let mut stabilizer = Stabilizer::new(4);
+        stabilizer.set_strength(0);
+        assert_eq!(stabilizer.strength(), Stabilizer::MIN_STRENGTH);
+        stabilizer.set_strength(11);
+        assert_eq!(stabilizer.strength(), Stabilizer::MAX_STRENGTH);
+        stabilizer.set_strength(7);
+        assert_eq!(stabilizer.strength(), 7);
+    }
+}
@@ -0,0 +1,113 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::FramebufferDraw;
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TouchMode {
+    OnlyUI,
+    Bezier,
+    Circles,
+    Diamonds,
+    FillDiamonds,
+}
+impl TouchMode {
+    pub fn toggle(self) -> Self {
+        match self {
+            TouchMode::OnlyUI => TouchMode::Bezier,
+            TouchMode::Bezier => TouchMode::Circles,
+            TouchMode::Circles => TouchMode::Diamonds,
+            TouchMode::Diamonds => TouchMode::FillDiamonds,
+            TouchMode::FillDiamonds => TouchMode::OnlyUI,
+        }
+    }
+
+    /// Draws the stamp of this mode at `position`, or returns `None` if fingers
+    /// don't draw in this mode.
+    pub fn draw_stamp(
+        self,
+        framebuffer: &mut dyn FramebufferDraw,
+        position: cgmath::Point2<f32>,
+    ) -> Option<mxcfb_rect> {
+        let rect = match self {
+            TouchMode::Bezier => {
+                let points = vec![
+                    (cgmath::vec2(-40.0, 0.0), 2.5),
+                    (cgmath::vec2(40.0, -60.0), 5.5),
+                    (cgmath::vec2(0.0, 0.0), 3.5),
+                    (cgmath::vec2(-40.0, 60.0), 6.5),
+                    (cgmath::vec2(-10.0, 50.0), 5.0),
+                    (cgmath::vec2(10.0, 45.0), 4.5),
+                    (cgmath::vec2(30.0, 55.0), 3.5),
+                    (cgmath::vec2(50.0, 65.0), 3.0),
+                    (cgmath::vec2(70.0, 40.0), 0.0),
+                ];
+                let mut rect = mxcfb_rect::invalid();
+                for window in points.windows(3).step_by(2) {
+                    rect = rect.merge_rect(&framebuffer.draw_dynamic_bezier(
+                        (position + window[0].0, window[0].1),
+                        (position + window[1].0, window[1].1),
+                        (position + window[2].0, window[2].1),
+                        100,
+                        color::BLACK,
+                    ));
+                }
+                rect
+            }
+            TouchMode::Circles => {
+                framebuffer.draw_circle(position.cast().unwrap(), 20, color::BLACK)
+            }
+
+            m @ TouchMode::Diamonds | m @ TouchMode::FillDiamonds => {
+                let position_int = position.cast().unwrap();
+                framebuffer.draw_polygon(
+                    &[
+                        position_int + cgmath::vec2(-10, 0),
+                        position_int + cgmath::vec2(0, 20),
+                        position_int + cgmath::vec2(10, 0),
+                        position_int + cgmath::vec2(0, -20),
+                    ],
+                    match m {
+                        TouchMode::Diamonds => false,
+                        TouchMode::FillDiamonds => true,
+                        _ => false,
+                    },
+                    color::BLACK,
+                )
+            }
+            TouchMode::OnlyUI => return None,
+        };
+        Some(rect)
+    }
+}
+
+impl fmt::Display for TouchMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self {
+            TouchMode::OnlyUI => "None",
+            TouchMode::Bezier => "Bezier",
+            TouchMode::Circles => "Circles",
+            TouchMode::Diamonds => "Diamonds",
+            TouchMode::FillDiamonds => "FDiamonds",
+        };
+        write!(f, "{}", mode)
+    }
+}
+
+impl FromStr for TouchMode {
+    type Err = String;
+
+    /// Parses the names modes are shown with.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "None" => Ok(TouchMode::OnlyUI),
+            "Bezier" => Ok(TouchMode::Bezier),
+            "Circles" => Ok(TouchMode::Circles),
+            "Diamonds" => Ok(TouchMode::Diamonds),
+            "FDiamonds" => Ok(TouchMode::FillDiamonds),
+            _ => Err(format!("Unknown touch mode: {}", s)),
+        }
+    }
+}